@@ -0,0 +1,954 @@
+use std;
+use std::collections::{
+  BTreeSet,
+  HashMap,
+  HashSet,
+};
+use std::fs::{
+  self,
+  File,
+};
+use std::io::{
+  BufReader,
+  BufWriter,
+  Read,
+  Write,
+};
+use std::path::{
+  Path,
+  PathBuf,
+};
+use std::process;
+
+use serde;
+use serde_json;
+
+use ::{
+  KVError,
+  Result,
+  Value,
+};
+
+use ::crypt;
+
+use ::oplog;
+
+use ::verify::{
+  self,
+  Report,
+};
+
+use ::format::{
+  self,
+  Format,
+  FORMAT_VERSION,
+};
+
+/// Where a `KVStore` keeps its values.
+///
+/// A backend is opened at a location and loaded once. Changes are made in memory through
+/// `get_mut`, `put` and `remove`, and only written back to the location by `flush`.
+pub trait Backend: Send {
+  /// Creates a backend for the store at `location`, without reading it yet.
+  fn open(location: &Path) -> Self where Self: Sized;
+
+  /// Whether there is a store at the backend's location.
+  fn exists(&self) -> bool;
+
+  /// Reads the store at the backend's location, replacing the values in memory.
+  fn load(&mut self) -> Result<()>;
+
+  /// Writes all changes back to the backend's location, creating the store if needed.
+  fn flush(&mut self) -> Result<()>;
+
+  fn get(&self, key: &str) -> Option<&Value>;
+
+  fn get_mut(&mut self, key: &str) -> Option<&mut Value>;
+
+  fn put(&mut self, key: String, value: Value) -> Option<Value>;
+
+  fn remove(&mut self, key: &str) -> Option<Value>;
+
+  fn keys(&self) -> Vec<&String>;
+
+  /// Expiry timestamps (seconds since the unix epoch) of keys with a time to live.
+  fn expires(&self) -> &HashMap<String, u64>;
+
+  fn expires_mut(&mut self) -> &mut HashMap<String, u64>;
+
+  /// Keys whose values are masked in output unless they are revealed.
+  fn secrets(&self) -> &BTreeSet<String>;
+
+  fn secrets_mut(&mut self) -> &mut BTreeSet<String>;
+
+  /// The operation log of the store, if the backend supports one.
+  fn log_path(&self) -> Option<PathBuf> {
+    None
+  }
+
+  /// Sequence number of the last operation log entry contained in the store.
+  fn log_seq(&self) -> u64 {
+    0
+  }
+
+  fn set_log_seq(&mut self, _seq: u64) {
+  }
+
+  /// Changes the format the store is written in from the next `flush` on.
+  fn set_format(&mut self, format: Format) -> Result<()> {
+    Err(KVError::UnknownError(format!("this backend cannot write the {} format", format)))
+  }
+
+  /// Checks the stored data for damage without loading it.
+  fn verify(&mut self) -> Result<Report> {
+    Err(KVError::UnknownError("this backend cannot be verified".to_string()))
+  }
+
+  /// Encrypts the store from the next `flush` on.
+  fn encrypt(&mut self) -> Result<()> {
+    Err(KVError::UnknownError("this backend cannot encrypt the store".to_string()))
+  }
+
+  fn is_encrypted(&self) -> bool {
+    false
+  }
+
+  /// The passphrase of an encrypted store, once it has been given or asked for.
+  fn passphrase(&self) -> Option<&str> {
+    None
+  }
+
+  /// Uses `passphrase` for an encrypted store instead of asking for it.
+  fn set_passphrase(&mut self, _passphrase: String) {
+  }
+}
+
+fn persist_error<S: ToString>(step: S) -> impl FnOnce(std::io::Error) -> KVError {
+  let step = step.to_string();
+  move |e| KVError::PersistError(step, e)
+}
+
+fn temp_path_for(path: &Path) -> Result<PathBuf> {
+  let file_name = path.file_name()
+    .and_then(|n| n.to_str())
+    .ok_or(KVError::UnknownError(format!("invalid store path {}", path.display())))?;
+
+  Ok(path.with_file_name(format!(".{}.{}.tmp", file_name, process::id())))
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+  let dir = match path.parent() {
+    Some(p) if p != Path::new("") => p,
+    _ => Path::new("."),
+  };
+
+  File::open(dir)
+    .and_then(|d| d.sync_all())
+    .map_err(persist_error(format!("syncing directory {}", dir.display())))
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_: &Path) -> Result<()> {
+  Ok(())
+}
+
+fn write_to_temp_file<F>(tmp_path: &Path, write: F) -> Result<()>
+  where F: FnOnce(&mut BufWriter<File>) -> Result<()> {
+  let file = File::create(tmp_path).map_err(persist_error(format!("creating {}", tmp_path.display())))?;
+  let mut writer = BufWriter::new(file);
+
+  write(&mut writer)?;
+
+  let file = writer.into_inner().map_err(|e| KVError::PersistError(format!("writing {}", tmp_path.display()), e.into_error()))?;
+  file.sync_all().map_err(persist_error(format!("syncing {}", tmp_path.display())))
+}
+
+/// Writes `path` without ever leaving a partially written file behind.
+///
+/// The content goes to a temporary file next to `path`, which is synced to disk and then
+/// renamed over the original. If any step fails, the previous file stays intact.
+fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+  where F: FnOnce(&mut BufWriter<File>) -> Result<()> {
+  let tmp_path = temp_path_for(path)?;
+
+  let result = write_to_temp_file(&tmp_path, write)
+    .and_then(|_| fs::rename(&tmp_path, path).map_err(persist_error(format!("renaming {} to {}", tmp_path.display(), path.display()))))
+    .and_then(|_| sync_parent_dir(path));
+
+  if result.is_err() {
+    let _ = fs::remove_file(&tmp_path);
+  }
+
+  result
+}
+
+fn remove_if_present(path: &Path) -> Result<()> {
+  match fs::remove_file(path) {
+    Ok(()) => Ok(()),
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    Err(e) => Err(KVError::PersistError(format!("removing {}", path.display()), e)),
+  }
+}
+
+fn is_zero(n: &u64) -> bool {
+  *n == 0
+}
+
+/// Keeps the store in memory only. `load` and `flush` do nothing, which makes it handy for tests.
+///
+/// It is also the shape in which the JSON file backend reads and writes the whole store.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MemoryBackend {
+  /// Always `FORMAT_VERSION`, since older stores are migrated when they are read.
+  version: u64,
+
+  content: HashMap<String, Value>,
+
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  expires: HashMap<String, u64>,
+
+  #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+  secrets: BTreeSet<String>,
+
+  #[serde(default, skip_serializing_if = "is_zero")]
+  log_seq: u64,
+}
+
+impl MemoryBackend {
+  pub fn new() -> MemoryBackend {
+    MemoryBackend {
+      version: FORMAT_VERSION,
+      content: HashMap::new(),
+      expires: HashMap::new(),
+      secrets: BTreeSet::new(),
+      log_seq: 0,
+    }
+  }
+
+  /// Reads a store in any `Format`, telling its format by the first bytes.
+  pub fn read<R: Read>(r: R) -> Result<MemoryBackend> {
+    MemoryBackend::read_format(r).map(|(memory, _)| memory)
+  }
+
+  /// Like `read`, but also returns the format the store was in.
+  pub fn read_format<R: Read>(mut r: R) -> Result<(MemoryBackend, Format)> {
+    let mut bytes = Vec::new();
+    r.read_to_end(&mut bytes)?;
+
+    match Format::detect(&bytes) {
+      Format::Json => Ok((format::read_json(&bytes)?, Format::Json)),
+      Format::Binary => Ok((format::read_binary(&bytes)?, Format::Binary)),
+    }
+  }
+
+  pub fn serialize(&self) -> Result<String> {
+    Ok(serde_json::to_string(self)?)
+  }
+
+  pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+    format::write_json(self, w)
+  }
+
+  pub fn write_format<W: Write>(&self, w: &mut W, format: Format) -> Result<()> {
+    match format {
+      Format::Json => self.write(w),
+      Format::Binary => format::write_binary(self, w),
+    }
+  }
+
+  /// Writes the store to `path` as JSON, replacing the file atomically and removing its
+  /// operation log like `JsonFileBackend::flush` does.
+  pub fn write_to_file(&self, path: &Path) -> Result<()> {
+    write_atomically(path, |w| self.write(w))?;
+
+    oplog::remove(&oplog::log_path_for(path))
+  }
+
+  /// Gives up the values, expiry timestamps and secret keys of the store.
+  pub fn into_entries(self) -> (HashMap<String, Value>, HashMap<String, u64>, BTreeSet<String>) {
+    (self.content, self.expires, self.secrets)
+  }
+}
+
+impl Default for MemoryBackend {
+  fn default() -> MemoryBackend {
+    MemoryBackend::new()
+  }
+}
+
+impl Backend for MemoryBackend {
+  fn open(_: &Path) -> MemoryBackend {
+    MemoryBackend::new()
+  }
+
+  fn exists(&self) -> bool {
+    true
+  }
+
+  fn load(&mut self) -> Result<()> {
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    Ok(())
+  }
+
+  fn get(&self, key: &str) -> Option<&Value> {
+    self.content.get(key)
+  }
+
+  fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+    self.content.get_mut(key)
+  }
+
+  fn put(&mut self, key: String, value: Value) -> Option<Value> {
+    self.content.insert(key, value)
+  }
+
+  fn remove(&mut self, key: &str) -> Option<Value> {
+    self.content.remove(key)
+  }
+
+  fn keys(&self) -> Vec<&String> {
+    self.content.keys().collect()
+  }
+
+  fn expires(&self) -> &HashMap<String, u64> {
+    &self.expires
+  }
+
+  fn expires_mut(&mut self) -> &mut HashMap<String, u64> {
+    &mut self.expires
+  }
+
+  fn secrets(&self) -> &BTreeSet<String> {
+    &self.secrets
+  }
+
+  fn secrets_mut(&mut self) -> &mut BTreeSet<String> {
+    &mut self.secrets
+  }
+
+  fn log_seq(&self) -> u64 {
+    self.log_seq
+  }
+
+  fn set_log_seq(&mut self, seq: u64) {
+    self.log_seq = seq;
+  }
+}
+
+/// Keeps the whole store in a single file, with an optional operation log next to it.
+///
+/// The file is JSON unless it is converted to the binary `Format`; it keeps the format it was read in.
+/// An encrypted store wraps either format with `crypt::seal` and has no operation log, which
+/// would keep the changes in plaintext.
+#[derive(Debug)]
+pub struct JsonFileBackend {
+  path: PathBuf,
+  memory: MemoryBackend,
+  format: Format,
+  encrypted: bool,
+  passphrase: Option<String>,
+}
+
+impl JsonFileBackend {
+  fn ask_passphrase(&mut self, new: bool) -> Result<String> {
+    if let Some(ref passphrase) = self.passphrase {
+      return Ok(passphrase.clone());
+    }
+
+    let passphrase = crypt::passphrase(new)?;
+    self.passphrase = Some(passphrase.clone());
+    Ok(passphrase)
+  }
+
+  /// Reads the file, decrypting it if it is encrypted.
+  fn read_plaintext(&mut self) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    File::open(&self.path)?.read_to_end(&mut bytes)?;
+
+    self.encrypted = crypt::is_encrypted(&bytes);
+
+    if self.encrypted {
+      let passphrase = self.ask_passphrase(false)?;
+      bytes = crypt::open(&bytes, &passphrase)?;
+    }
+
+    Ok(bytes)
+  }
+}
+
+impl Backend for JsonFileBackend {
+  fn open(location: &Path) -> JsonFileBackend {
+    JsonFileBackend {
+      path: location.to_path_buf(),
+      memory: MemoryBackend::new(),
+      format: Format::Json,
+      encrypted: false,
+      passphrase: None,
+    }
+  }
+
+  fn exists(&self) -> bool {
+    self.path.exists()
+  }
+
+  fn load(&mut self) -> Result<()> {
+    let bytes = self.read_plaintext()?;
+    let (memory, format) = MemoryBackend::read_format(&bytes[..])?;
+    self.memory = memory;
+    self.format = format;
+
+    Ok(())
+  }
+
+  /// Replaces the file atomically. Since it then contains all changes, the operation log is removed.
+  fn flush(&mut self) -> Result<()> {
+    let mut bytes = Vec::new();
+    self.memory.write_format(&mut bytes, self.format)?;
+
+    if self.encrypted {
+      let passphrase = self.ask_passphrase(false)?;
+      bytes = crypt::seal(&bytes, &passphrase)?;
+    }
+
+    write_atomically(&self.path, |w| Ok(w.write_all(&bytes)?))?;
+
+    oplog::remove(&oplog::log_path_for(&self.path))
+  }
+
+  fn get(&self, key: &str) -> Option<&Value> {
+    self.memory.get(key)
+  }
+
+  fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+    self.memory.get_mut(key)
+  }
+
+  fn put(&mut self, key: String, value: Value) -> Option<Value> {
+    self.memory.put(key, value)
+  }
+
+  fn remove(&mut self, key: &str) -> Option<Value> {
+    self.memory.remove(key)
+  }
+
+  fn keys(&self) -> Vec<&String> {
+    self.memory.keys()
+  }
+
+  fn expires(&self) -> &HashMap<String, u64> {
+    self.memory.expires()
+  }
+
+  fn expires_mut(&mut self) -> &mut HashMap<String, u64> {
+    self.memory.expires_mut()
+  }
+
+  fn secrets(&self) -> &BTreeSet<String> {
+    self.memory.secrets()
+  }
+
+  fn secrets_mut(&mut self) -> &mut BTreeSet<String> {
+    self.memory.secrets_mut()
+  }
+
+  fn log_path(&self) -> Option<PathBuf> {
+    if self.encrypted {
+      None
+    } else {
+      Some(oplog::log_path_for(&self.path))
+    }
+  }
+
+  fn log_seq(&self) -> u64 {
+    self.memory.log_seq()
+  }
+
+  fn set_log_seq(&mut self, seq: u64) {
+    self.memory.set_log_seq(seq);
+  }
+
+  fn set_format(&mut self, format: Format) -> Result<()> {
+    self.format = format;
+    Ok(())
+  }
+
+  fn verify(&mut self) -> Result<Report> {
+    let bytes = self.read_plaintext()?;
+
+    Ok(verify::verify(&bytes))
+  }
+
+  fn encrypt(&mut self) -> Result<()> {
+    self.ask_passphrase(true)?;
+    self.encrypted = true;
+
+    Ok(())
+  }
+
+  fn is_encrypted(&self) -> bool {
+    self.encrypted
+  }
+
+  fn passphrase(&self) -> Option<&str> {
+    self.passphrase.as_deref()
+  }
+
+  fn set_passphrase(&mut self, passphrase: String) {
+    self.passphrase = Some(passphrase);
+  }
+}
+
+const EXPIRES_FILE: &str = ".expires.json";
+
+const SECRETS_FILE: &str = ".secrets.json";
+
+/// Reads one of the JSON files a `DirBackend` keeps next to the values, which is missing while empty.
+fn read_metadata<T: serde::Deserialize + Default>(path: &Path) -> Result<T> {
+  if path.exists() {
+    Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+  } else {
+    Ok(T::default())
+  }
+}
+
+fn write_metadata<T: serde::Serialize>(path: &Path, metadata: &T, is_empty: bool) -> Result<()> {
+  if is_empty {
+    remove_if_present(path)
+  } else {
+    write_atomically(path, |w| Ok(serde_json::to_writer_pretty(w, metadata)?))
+  }
+}
+
+/// File name extensions of the value types in a `DirBackend`, see `DirBackend::extension_for`.
+const EXTENSIONS: [&str; 5] = ["txt", "list", "int", "map", "set"];
+
+/// Turns `key` into a file name. Bytes other than lowercase ASCII letters, digits, `-`, `_` and
+/// `.` are written as `%XX`, as is a leading `.`, which is reserved for the backend's own files.
+/// Uppercase letters are escaped so that keys differing only in case do not share a file on
+/// case-insensitive filesystems. The empty key is written as `%`.
+fn escape_key(key: &str) -> String {
+  if key.is_empty() {
+    return "%".to_string();
+  }
+
+  let mut name = String::new();
+
+  for (i, &b) in key.as_bytes().iter().enumerate() {
+    match b {
+      b'.' if i == 0 => name.push_str("%2E"),
+      b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' => name.push(b as char),
+      _ => name.push_str(&format!("%{:02X}", b)),
+    }
+  }
+
+  name
+}
+
+fn unescape_key(name: &str) -> Option<String> {
+  if name == "%" {
+    return Some(String::new());
+  }
+
+  let bytes = name.as_bytes();
+  let mut key = Vec::new();
+  let mut i = 0;
+
+  while i < bytes.len() {
+    if bytes[i] == b'%' {
+      let hex = name.get(i + 1..i + 3)?;
+      key.push(u8::from_str_radix(hex, 16).ok()?);
+      i += 3;
+    } else {
+      key.push(bytes[i]);
+      i += 1;
+    }
+  }
+
+  String::from_utf8(key).ok()
+}
+
+/// Escapes `s` so it fits on a single line, also escaping `separator` if given.
+fn escape_line(s: &str, separator: Option<char>) -> String {
+  let mut line = String::new();
+
+  for c in s.chars() {
+    match c {
+      '\\' => line.push_str("\\\\"),
+      '\n' => line.push_str("\\n"),
+      '\r' => line.push_str("\\r"),
+      c if Some(c) == separator => {
+        line.push('\\');
+        line.push(c);
+      },
+      c => line.push(c),
+    }
+  }
+
+  line
+}
+
+/// Splits `line` at the first unescaped `separator` and unescapes the parts.
+fn unescape_line(line: &str, separator: Option<char>) -> (String, Option<String>) {
+  let mut first = String::new();
+  let mut second: Option<String> = None;
+  let mut chars = line.chars();
+
+  while let Some(c) = chars.next() {
+    let c = match c {
+      '\\' => match chars.next() {
+        Some('n') => '\n',
+        Some('r') => '\r',
+        Some(c) => c,
+        None => '\\',
+      },
+      c if Some(c) == separator && second.is_none() => {
+        second = Some(String::new());
+        continue;
+      },
+      c => c,
+    };
+
+    second.as_mut().unwrap_or(&mut first).push(c);
+  }
+
+  (first, second)
+}
+
+/// Keeps every key in its own file inside a store directory, so changes show up per key in
+/// version control.
+///
+/// A file is named after its escaped key plus an extension for the value type: strings are
+/// written as is to `.txt` files, lists to `.list` files with one item per line, integers to
+/// `.int` files, maps to `.map` files with one `field=value` line per field and sets to `.set`
+/// files with one member per line. Newlines and backslashes in items are escaped with a backslash.
+/// Files without one of these extensions are ignored. Only the files of keys changed since the
+/// last `load` are rewritten on `flush`.
+#[derive(Debug)]
+pub struct DirBackend {
+  dir: PathBuf,
+  memory: MemoryBackend,
+  changed: HashSet<String>,
+  removed: HashSet<String>,
+}
+
+impl DirBackend {
+  fn extension_for(value: &Value) -> &'static str {
+    match *value {
+      Value::StringValue(_) => "txt",
+      Value::ListValue(_) => "list",
+      Value::IntValue(_) => "int",
+      Value::MapValue(_) => "map",
+      Value::SetValue(_) => "set",
+    }
+  }
+
+  fn key_path(&self, key: &str, extension: &str) -> PathBuf {
+    self.dir.join(format!("{}.{}", escape_key(key), extension))
+  }
+
+  fn encode_value(value: &Value) -> String {
+    let lines = |items: Vec<String>| items.iter().map(|item| format!("{}\n", item)).collect::<String>();
+
+    match *value {
+      Value::StringValue(ref s) => s.clone(),
+      Value::ListValue(ref list) => lines(list.iter().map(|item| escape_line(item, None)).collect()),
+      Value::IntValue(i) => format!("{}\n", i),
+      Value::MapValue(ref map) => {
+        let mut fields: Vec<String> = map.iter()
+          .map(|(field, value)| format!("{}={}", escape_line(field, Some('=')), escape_line(value, None)))
+          .collect();
+        fields.sort();
+        lines(fields)
+      },
+      Value::SetValue(ref set) => lines(set.iter().map(|member| escape_line(member, None)).collect()),
+    }
+  }
+
+  fn decode_value(extension: &str, content: String) -> Option<Value> {
+    // only the newline `encode_value` ends each line with, as items may be empty
+    let lines = || if content.is_empty() { Vec::new() } else { content.strip_suffix('\n').unwrap_or(&content).split('\n').collect() };
+
+    match extension {
+      "txt" => Some(Value::StringValue(content.clone())),
+      "list" => Some(Value::ListValue(lines().into_iter().map(|line| unescape_line(line, None).0).collect())),
+      "int" => content.trim().parse().ok().map(Value::IntValue),
+      "map" => lines().into_iter()
+        .map(|line| match unescape_line(line, Some('=')) {
+          (field, Some(value)) => Some((field, value)),
+          _ => None,
+        })
+        .collect::<Option<HashMap<String, String>>>()
+        .map(Value::MapValue),
+      "set" => Some(Value::SetValue(lines().into_iter().map(|line| unescape_line(line, None).0).collect())),
+      _ => None,
+    }
+  }
+
+  /// Reads the file at `path`, returning its key and value, or None if it does not belong to the store.
+  fn read_entry(path: &Path) -> Result<Option<(String, Value)>> {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+
+    let (stem, extension) = match name.rsplit_once('.') {
+      Some((stem, extension)) if !name.starts_with('.') && EXTENSIONS.contains(&extension) => (stem, extension),
+      _ => return Ok(None),
+    };
+
+    let invalid = || KVError::UnknownError(format!("invalid store file {}", path.display()));
+
+    let key = unescape_key(stem).ok_or_else(invalid)?;
+    let value = DirBackend::decode_value(extension, fs::read_to_string(path)?).ok_or_else(invalid)?;
+
+    Ok(Some((key, value)))
+  }
+
+  /// Removes the files of `key`, except the one with extension `keep`.
+  fn remove_files(&self, key: &str, keep: Option<&str>) -> Result<()> {
+    for extension in EXTENSIONS.iter().filter(|&&e| Some(e) != keep) {
+      remove_if_present(&self.key_path(key, extension))?;
+    }
+
+    Ok(())
+  }
+}
+
+impl Backend for DirBackend {
+  fn open(location: &Path) -> DirBackend {
+    DirBackend {
+      dir: location.to_path_buf(),
+      memory: MemoryBackend::new(),
+      changed: HashSet::new(),
+      removed: HashSet::new(),
+    }
+  }
+
+  fn exists(&self) -> bool {
+    self.dir.is_dir()
+  }
+
+  fn load(&mut self) -> Result<()> {
+    let mut memory = MemoryBackend::new();
+
+    for entry in fs::read_dir(&self.dir)? {
+      if let Some((key, value)) = DirBackend::read_entry(&entry?.path())? {
+        memory.put(key, value);
+      }
+    }
+
+    memory.expires = read_metadata(&self.dir.join(EXPIRES_FILE))?;
+    memory.secrets = read_metadata(&self.dir.join(SECRETS_FILE))?;
+
+    self.memory = memory;
+    self.changed.clear();
+    self.removed.clear();
+
+    Ok(())
+  }
+
+  fn flush(&mut self) -> Result<()> {
+    fs::create_dir_all(&self.dir).map_err(persist_error(format!("creating {}", self.dir.display())))?;
+
+    for key in &self.removed {
+      self.remove_files(key, None)?;
+    }
+
+    for key in &self.changed {
+      if let Some(value) = self.memory.get(key) {
+        let extension = DirBackend::extension_for(value);
+        write_atomically(&self.key_path(key, extension), |w| Ok(w.write_all(DirBackend::encode_value(value).as_bytes())?))?;
+        self.remove_files(key, Some(extension))?;
+      }
+    }
+
+    write_metadata(&self.dir.join(EXPIRES_FILE), &self.memory.expires, self.memory.expires.is_empty())?;
+    write_metadata(&self.dir.join(SECRETS_FILE), &self.memory.secrets, self.memory.secrets.is_empty())?;
+
+    self.changed.clear();
+    self.removed.clear();
+
+    Ok(())
+  }
+
+  fn get(&self, key: &str) -> Option<&Value> {
+    self.memory.get(key)
+  }
+
+  fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+    if self.memory.get(key).is_some() {
+      self.changed.insert(key.to_string());
+    }
+
+    self.memory.get_mut(key)
+  }
+
+  fn put(&mut self, key: String, value: Value) -> Option<Value> {
+    self.removed.remove(&key);
+    self.changed.insert(key.clone());
+
+    self.memory.put(key, value)
+  }
+
+  fn remove(&mut self, key: &str) -> Option<Value> {
+    let value = self.memory.remove(key);
+
+    if value.is_some() {
+      self.changed.remove(key);
+      self.removed.insert(key.to_string());
+    }
+
+    value
+  }
+
+  fn keys(&self) -> Vec<&String> {
+    self.memory.keys()
+  }
+
+  fn expires(&self) -> &HashMap<String, u64> {
+    self.memory.expires()
+  }
+
+  fn expires_mut(&mut self) -> &mut HashMap<String, u64> {
+    self.memory.expires_mut()
+  }
+
+  fn secrets(&self) -> &BTreeSet<String> {
+    self.memory.secrets()
+  }
+
+  fn secrets_mut(&mut self) -> &mut BTreeSet<String> {
+    self.memory.secrets_mut()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use ::KVStore;
+  use ::test_util::TempDir;
+
+  #[test]
+  fn test_escape_key() {
+    for key in &["plain", "with space", "a/b", ".hidden", "%", "", "ünïcode", "x.y", "Key"] {
+      assert_eq!(Some(key.to_string()), unescape_key(&escape_key(key)));
+    }
+
+    assert_eq!("a%2Fb", escape_key("a/b"));
+    assert_eq!("%2Ehidden", escape_key(".hidden"));
+    assert_eq!("%4Bey", escape_key("Key"));
+    assert_ne!(escape_key("key").to_lowercase(), escape_key("Key").to_lowercase());
+    assert!(unescape_key("broken%2").is_none());
+  }
+
+  #[test]
+  fn test_dir_backend_round_trip() {
+    let temp = TempDir::new("backend-dir");
+    let dir = temp.join("store");
+
+    let mut kvs: KVStore<DirBackend> = KVStore::open(&dir);
+    assert!(!kvs.exists());
+
+    kvs.put("config/name", "value");
+    kvs.put_empty_list("list");
+    kvs.push_value("list", "a").unwrap();
+    kvs.put("token", "secret");
+    kvs.expire("token", ::std::time::Duration::from_secs(3600));
+    kvs.flush().unwrap();
+
+    assert_eq!("value", fs::read_to_string(dir.join("config%2Fname.txt")).unwrap());
+    assert_eq!("a\n", fs::read_to_string(dir.join("list.list")).unwrap());
+
+    let mut read: KVStore<DirBackend> = KVStore::open(&dir);
+    read.load().unwrap();
+
+    match read.get(&"list") {
+      Some(Value::ListValue(list)) => assert_eq!(vec!["a".to_string()], *list),
+      other => panic!("unexpected value {:?}", other),
+    }
+    assert!(read.get_ttl(&"token").is_some());
+
+    read.drop("config/name");
+    read.drop("list");
+    read.increment("list", 3).unwrap();
+    read.flush().unwrap();
+
+    assert!(!dir.join("config%2Fname.txt").exists());
+    assert!(!dir.join("list.list").exists());
+    assert_eq!("3\n", fs::read_to_string(dir.join("list.int")).unwrap());
+  }
+
+  #[test]
+  fn test_file_backend_keeps_format() {
+    let dir = TempDir::new("backend-format");
+    let path = dir.join(".kvs.json");
+
+    let mut kvs = ::init(&path).unwrap();
+    kvs.put("key", "value");
+    kvs.backend_mut().set_format(Format::Binary).unwrap();
+    kvs.flush().unwrap();
+
+    let mut read = KVStore::read_from_file(&path).unwrap();
+    assert_eq!(Format::Binary, Format::detect(&fs::read(&path).unwrap()));
+
+    read.put("other", "value");
+    read.flush().unwrap();
+    assert_eq!(Format::Binary, Format::detect(&fs::read(&path).unwrap()));
+    assert!(KVStore::read_from_file(&path).unwrap().has_key("other"));
+
+    let mut dir_kvs: KVStore<DirBackend> = KVStore::open(&dir.join("store"));
+    assert!(dir_kvs.backend_mut().set_format(Format::Binary).is_err());
+  }
+
+  #[test]
+  fn test_encrypted_file_backend() {
+    let dir = TempDir::new("backend-encrypted");
+    let path = dir.join(".kvs.json");
+
+    let mut kvs: KVStore = KVStore::open(&path);
+    kvs.backend_mut().set_passphrase("secret".to_string());
+    kvs.backend_mut().encrypt().unwrap();
+    kvs.put("token", "abc123");
+    kvs.flush().unwrap();
+
+    let bytes = fs::read(&path).unwrap();
+    assert!(crypt::is_encrypted(&bytes));
+    assert!(!bytes.windows(6).any(|w| w == b"abc123"));
+    assert_eq!(None, kvs.backend().log_path());
+
+    let mut read: KVStore = KVStore::open(&path);
+    read.backend_mut().set_passphrase("secret".to_string());
+    read.load().unwrap();
+    assert_eq!(Some(&Value::StringValue("abc123".to_string())), read.backend().get("token"));
+
+    let mut wrong: KVStore = KVStore::open(&path);
+    wrong.backend_mut().set_passphrase("guess".to_string());
+    match wrong.load() {
+      Err(KVError::DecryptionFailed) => (),
+      other => panic!("unexpected result {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_encode_values() {
+    let list = Value::ListValue(vec!["multi\nline".to_string(), "".to_string(), "back\\slash".to_string()]);
+    let mut map = HashMap::new();
+    map.insert("a=b".to_string(), "c=d".to_string());
+    map.insert("port".to_string(), "22".to_string());
+    let map = Value::MapValue(map);
+
+    assert_eq!("multi\\nline\n\nback\\\\slash\n", DirBackend::encode_value(&list));
+    assert_eq!("a\\=b=c=d\nport=22\n", DirBackend::encode_value(&map));
+
+    let strings = |items: &[&str]| Value::ListValue(items.iter().map(|s| s.to_string()).collect());
+    let lists = [strings(&["a", ""]), strings(&["", ""]), strings(&[""]), strings(&["", "a", "", ""])];
+
+    for value in lists.iter().chain(&[list, map, Value::ListValue(Vec::new()), Value::StringValue("".to_string())]) {
+      let extension = DirBackend::extension_for(value);
+      let decoded = DirBackend::decode_value(extension, DirBackend::encode_value(value)).unwrap();
+      assert_eq!(*value, decoded);
+    }
+  }
+}
@@ -5,11 +5,14 @@ use std::fmt::Formatter;
 
 use std::str::FromStr;
 
+use ::format::Format;
+
 
 #[derive(Debug)]
 pub enum Error {
   InvalidCommand(String),
   TooFewArguments(String, usize, usize),
+  InvalidArgument(String, String),
   NoCommand,
   UnknownError(String),
 }
@@ -19,6 +22,7 @@ impl Display for Error {
     match *self {
       Error::InvalidCommand(ref cmd) => write!(f, "invalid command: {}", cmd),
       Error::TooFewArguments(ref cmd, ref expected, ref actual) => write!(f, "command {} expects {} arguments, got {}", cmd, expected, actual),
+      Error::InvalidArgument(ref cmd, ref arg) => write!(f, "invalid argument for command {}: {}", cmd, arg),
       Error::NoCommand => write!(f, "no command given"),
       Error::UnknownError(ref msg) => write!(f, "unexpected error: {}", msg),
     }
@@ -33,24 +37,64 @@ impl Error {
 
 type Result<V> = std::result::Result<V, Error>;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Command {
-  Init,
+  /// Creates the store, encrypted if the flag is set.
+  Init(bool),
 
   ListKeys,
 
   PutString(String, String),
+  PutSecret(String, String),
+  PutStringWithTtl(String, String, u64),
   Drop(String),
 
+  Expire(String, u64),
+  Ttl(String),
+  Persist(String),
+  Purge,
+
   CreateEmptyList(String),
   PushListValue(String, String),
+  /// Pushes the values to the back, creating the list if missing.
+  PushListValues(String, Vec<String>),
+  /// Pushes the values to the front one after another, creating the list if missing.
+  PushFrontListValues(String, Vec<String>),
   PopListValue(String),
   ClearList(String),
 
+  Increment(String, i64),
+  Decrement(String, i64),
+
+  MapSet(String, String, String),
+  MapGet(String, String),
+  MapDelete(String, String),
+  MapKeys(String),
+  MapGetAll(String),
+
+  SetAdd(String, String),
+  SetRemove(String, String),
+  SetIsMember(String, String),
+  SetCardinality(String),
+  SetUnion(Vec<String>),
+  SetIntersection(Vec<String>),
+  SetDifference(Vec<String>),
+
+  /// Runs the onMiss hooks for a missing key, so lookups on behalf of other commands should read
+  /// the store directly instead.
   Get(String),
+  GetRevealed(String),
+  GetCached(String),
+
+  Compact,
+  Convert(Format),
+  Verify,
+  Repair(Option<String>),
+  /// Lists the hooks that would run for the command.
+  ListHooks(Box<Command>),
 }
 
-fn assert_length(v: &Vec<String>, l: usize) -> Result<&Vec<String>> {
+fn assert_length(v: &[String], l: usize) -> Result<&[String]> {
   if v.len() >= l {
     Ok(v)
   } else {
@@ -58,6 +102,67 @@ fn assert_length(v: &Vec<String>, l: usize) -> Result<&Vec<String>> {
   }
 }
 
+fn parse_arg<T: FromStr>(v: &[String], i: usize) -> Result<T> {
+  v[i].parse().map_err(|_| Error::InvalidArgument(v[0].clone(), v[i].clone()))
+}
+
+fn parse_amount(v: &[String]) -> Result<i64> {
+  if v.len() > 2 {
+    parse_arg(v, 2)
+  } else {
+    Ok(1)
+  }
+}
+
+fn parse_init(v: &[String]) -> Result<Command> {
+  match v.get(1).map(String::as_str) {
+    None => Ok(Command::Init(false)),
+    Some("--encrypted") => Ok(Command::Init(true)),
+    Some(arg) => Err(Error::InvalidArgument(v[0].clone(), arg.to_string())),
+  }
+}
+
+fn parse_convert(v: &[String]) -> Result<Command> {
+  let v = assert_length(v, 3)?;
+
+  if v[1] != "--to" {
+    return Err(Error::InvalidArgument(v[0].clone(), v[1].clone()));
+  }
+
+  parse_arg(v, 2).map(Command::Convert)
+}
+
+fn parse_hooks(v: &[String]) -> Result<Command> {
+  let v = assert_length(v, 3)?;
+
+  if v[1] != "list" {
+    return Err(Error::InvalidArgument(v[0].clone(), v[1].clone()));
+  }
+
+  Command::from_strings(v[2..].to_vec()).map(|command| Command::ListHooks(Box::new(command)))
+}
+
+fn parse_get(v: &[String]) -> Result<Command> {
+  if v.len() > 1 && v[1] == "--reveal" {
+    assert_length(v, 3).map(|v| Command::GetRevealed(v[2].clone()))
+  } else if v.len() > 1 && v[1] == "--cache" {
+    assert_length(v, 3).map(|v| Command::GetCached(v[2].clone()))
+  } else {
+    assert_length(v, 2).map(|v| Command::Get(v[1].clone()))
+  }
+}
+
+fn parse_put(v: &[String]) -> Result<Command> {
+  if v.len() > 1 && v[1] == "--ttl" {
+    let v = assert_length(v, 5)?;
+    Ok(Command::PutStringWithTtl(v[3].clone(), v[4..].join(" "), parse_arg(v, 2)?))
+  } else if v.len() > 1 && v[1] == "--secret" {
+    assert_length(v, 4).map(|v| Command::PutSecret(v[2].clone(), v[3..].join(" ")))
+  } else {
+    assert_length(v, 3).map(|v| Command::PutString(v[1].clone(), v[2..].join(" ")))
+  }
+}
+
 impl FromStr for Command {
   type Err = Error;
 
@@ -75,36 +180,138 @@ impl Command {
     }
 
     match strings[0].as_str() {
-      "init" => Ok(Command::Init),
+      "init" => parse_init(&strings),
 
-      "put" => assert_length(&strings, 3).map(|v| Command::PutString(v[1].clone(), v[2..].join(" "))),
+      "put" => parse_put(&strings),
       "drop" => assert_length(&strings, 2).map(|v| Command::Drop(v[1].clone())),
 
+      "expire" => assert_length(&strings, 3).and_then(|v| Ok(Command::Expire(v[1].clone(), parse_arg(v, 2)?))),
+      "ttl" => assert_length(&strings, 2).map(|v| Command::Ttl(v[1].clone())),
+      "persist" => assert_length(&strings, 2).map(|v| Command::Persist(v[1].clone())),
+      "purge" => Ok(Command::Purge),
+
       "emptyList" => assert_length(&strings, 2).map(|v| Command::CreateEmptyList(v[1].clone())),
       "push" => assert_length(&strings, 3).map(|v| Command::PushListValue(v[1].clone(), v[2..].join(" "))),
       "pop" => assert_length(&strings, 2).map(|v| Command::PopListValue(v[1].clone())),
       "clear" => assert_length(&strings, 2).map(|v| Command::ClearList(v[1].clone())),
 
-      "get" => assert_length(&strings, 2).map(|v| Command::Get(v[1].clone())),
+      "incr" => assert_length(&strings, 2).and_then(|v| Ok(Command::Increment(v[1].clone(), parse_amount(v)?))),
+      "decr" => assert_length(&strings, 2).and_then(|v| Ok(Command::Decrement(v[1].clone(), parse_amount(v)?))),
+
+      "hset" => assert_length(&strings, 4).map(|v| Command::MapSet(v[1].clone(), v[2].clone(), v[3..].join(" "))),
+      "hget" => assert_length(&strings, 3).map(|v| Command::MapGet(v[1].clone(), v[2].clone())),
+      "hdel" => assert_length(&strings, 3).map(|v| Command::MapDelete(v[1].clone(), v[2].clone())),
+      "hkeys" => assert_length(&strings, 2).map(|v| Command::MapKeys(v[1].clone())),
+      "hgetall" => assert_length(&strings, 2).map(|v| Command::MapGetAll(v[1].clone())),
+
+      "sadd" => assert_length(&strings, 3).map(|v| Command::SetAdd(v[1].clone(), v[2..].join(" "))),
+      "srem" => assert_length(&strings, 3).map(|v| Command::SetRemove(v[1].clone(), v[2..].join(" "))),
+      "sismember" => assert_length(&strings, 3).map(|v| Command::SetIsMember(v[1].clone(), v[2..].join(" "))),
+      "scard" => assert_length(&strings, 2).map(|v| Command::SetCardinality(v[1].clone())),
+      "sunion" => assert_length(&strings, 2).map(|v| Command::SetUnion(v[1..].to_vec())),
+      "sinter" => assert_length(&strings, 2).map(|v| Command::SetIntersection(v[1..].to_vec())),
+      "sdiff" => assert_length(&strings, 2).map(|v| Command::SetDifference(v[1..].to_vec())),
+
+      "get" => parse_get(&strings),
 
       "ls" => Ok(Command::ListKeys),
 
+      "compact" => Ok(Command::Compact),
+      "convert" => parse_convert(&strings),
+      "verify" => Ok(Command::Verify),
+      "repair" => Ok(Command::Repair(strings.get(1).cloned())),
+      "hooks" => parse_hooks(&strings),
+
       cmd => Err(Error::InvalidCommand(cmd.to_string()))
     }
   }
 
   pub fn is_change(&self) -> bool {
       match *self {
-          Command::Init |
+          Command::Init(..) |
               Command::PutString(..) |
+              Command::PutSecret(..) |
+              Command::PutStringWithTtl(..) |
               Command::Drop(..) |
+              Command::Expire(..) |
+              Command::Persist(..) |
+              Command::Purge |
+              Command::Compact |
+              Command::Convert(..) |
               Command::CreateEmptyList(..) |
               Command::PushListValue(..) |
+              Command::PushListValues(..) |
+              Command::PushFrontListValues(..) |
               Command::PopListValue(..) |
-              Command::ClearList(..) => true,
+              Command::ClearList(..) |
+              Command::Increment(..) |
+              Command::Decrement(..) |
+              Command::MapSet(..) |
+              Command::MapDelete(..) |
+              Command::SetAdd(..) |
+              Command::SetRemove(..) => true,
         _ => false,
       }
   }
+
+  /// Whether the command may write to the store, so that it has to be locked exclusively.
+  /// A `get --cache` writes the value it fetched for a missing key.
+  pub fn may_change(&self) -> bool {
+    match *self {
+      Command::GetCached(..) => true,
+      _ => self.is_change(),
+    }
+  }
+
+  /// Whether the command needs the whole store to be written, rather than just a log entry.
+  pub fn needs_snapshot(&self) -> bool {
+    match *self {
+      Command::Init(..) | Command::Compact | Command::Convert(..) => true,
+      _ => false,
+    }
+  }
+
+  /// The key the command works on, if it works on a single one.
+  pub fn key(&self) -> Option<&str> {
+    match *self {
+      Command::PutString(ref key, _) |
+        Command::PutSecret(ref key, _) |
+        Command::PutStringWithTtl(ref key, _, _) |
+        Command::Drop(ref key) |
+        Command::Expire(ref key, _) |
+        Command::Ttl(ref key) |
+        Command::Persist(ref key) |
+        Command::CreateEmptyList(ref key) |
+        Command::PushListValue(ref key, _) |
+        Command::PushListValues(ref key, _) |
+        Command::PushFrontListValues(ref key, _) |
+        Command::PopListValue(ref key) |
+        Command::ClearList(ref key) |
+        Command::Increment(ref key, _) |
+        Command::Decrement(ref key, _) |
+        Command::MapSet(ref key, _, _) |
+        Command::MapGet(ref key, _) |
+        Command::MapDelete(ref key, _) |
+        Command::MapKeys(ref key) |
+        Command::MapGetAll(ref key) |
+        Command::SetAdd(ref key, _) |
+        Command::SetRemove(ref key, _) |
+        Command::SetIsMember(ref key, _) |
+        Command::SetCardinality(ref key) |
+        Command::Get(ref key) |
+        Command::GetRevealed(ref key) |
+        Command::GetCached(ref key) => Some(key.as_str()),
+      _ => None,
+    }
+  }
+
+  /// Whether the command works on the stored bytes, so that the store must not be loaded first.
+  pub fn reads_raw_store(&self) -> bool {
+    match *self {
+      Command::Verify | Command::Repair(..) => true,
+      _ => false,
+    }
+  }
 }
 
 #[cfg(test)]
@@ -115,7 +322,9 @@ mod tests {
   fn test_read_init() {
     let cmd = Command::from_str("init").unwrap();
 
-    assert_eq!(cmd, Command::Init);
+    assert_eq!(cmd, Command::Init(false));
+    assert_eq!(Command::from_str("init --encrypted").unwrap(), Command::Init(true));
+    assert!(Command::from_str("init --plain").is_err());
   }
 
   #[test]
@@ -157,6 +366,109 @@ mod tests {
     }
   }
 
+  #[test]
+  fn test_incr_decr() {
+    assert_eq!(Command::from_str("incr count").unwrap(), Command::Increment("count".to_string(), 1));
+    assert_eq!(Command::from_str("decr count 5").unwrap(), Command::Decrement("count".to_string(), 5));
+  }
+
+  #[test]
+  fn test_incr_invalid_amount() {
+    let err = Command::from_str("incr count many").err().unwrap();
+
+    if let Error::InvalidArgument(cmd, arg) = err {
+      assert_eq!(cmd, "incr".to_string());
+      assert_eq!(arg, "many".to_string());
+    } else {
+      assert!(false);
+    }
+  }
+
+  #[test]
+  fn test_map_commands() {
+    assert_eq!(Command::from_str("hset host ip 10.0.0.1").unwrap(), Command::MapSet("host".to_string(), "ip".to_string(), "10.0.0.1".to_string()));
+    assert_eq!(Command::from_str("hget host ip").unwrap(), Command::MapGet("host".to_string(), "ip".to_string()));
+    assert_eq!(Command::from_str("hgetall host").unwrap(), Command::MapGetAll("host".to_string()));
+  }
+
+  #[test]
+  fn test_set_commands() {
+    assert_eq!(Command::from_str("sadd hosts web 1").unwrap(), Command::SetAdd("hosts".to_string(), "web 1".to_string()));
+    assert_eq!(Command::from_str("sunion a b c").unwrap(), Command::SetUnion(vec!["a".to_string(), "b".to_string(), "c".to_string()]));
+  }
+
+  #[test]
+  fn test_put_with_ttl() {
+    let cmd = Command::from_str("put --ttl 3600 token abc def").unwrap();
+
+    assert_eq!(cmd, Command::PutStringWithTtl("token".to_string(), "abc def".to_string(), 3600));
+  }
+
+  #[test]
+  fn test_put_with_ttl_too_few_arguments() {
+    let err = Command::from_str("put --ttl 3600 token").err().unwrap();
+
+    if let Error::TooFewArguments(_, expected, actual) = err {
+      assert_eq!(expected, 5);
+      assert_eq!(actual, 4);
+    } else {
+      assert!(false);
+    }
+  }
+
+  #[test]
+  fn test_secrets() {
+    assert_eq!(Command::from_str("put --secret token abc def").unwrap(), Command::PutSecret("token".to_string(), "abc def".to_string()));
+    assert_eq!(Command::from_str("get --reveal token").unwrap(), Command::GetRevealed("token".to_string()));
+    assert_eq!(Command::from_str("get token").unwrap(), Command::Get("token".to_string()));
+    assert!(Command::from_str("put --secret token").is_err());
+  }
+
+  #[test]
+  fn test_key() {
+    assert_eq!(Some("list"), Command::from_str("push list a").unwrap().key());
+    assert_eq!(Some("map"), Command::from_str("hset map field value").unwrap().key());
+    assert_eq!(None, Command::Purge.key());
+    assert_eq!(None, Command::from_str("sunion a b").unwrap().key());
+  }
+
+  #[test]
+  fn test_list_hooks() {
+    assert_eq!(Command::from_str("hooks list drop deploy.web").unwrap(), Command::ListHooks(Box::new(Command::Drop("deploy.web".to_string()))));
+    assert!(!Command::from_str("hooks list purge").unwrap().is_change());
+    assert!(Command::from_str("hooks show purge").is_err());
+    assert!(Command::from_str("hooks list").is_err());
+  }
+
+  #[test]
+  fn test_get_cached() {
+    assert_eq!(Command::from_str("get --cache host").unwrap(), Command::GetCached("host".to_string()));
+    assert!(Command::from_str("get --cache").is_err());
+    assert!(Command::GetCached("host".to_string()).may_change());
+    assert!(!Command::GetCached("host".to_string()).is_change());
+  }
+
+  #[test]
+  fn test_expire() {
+    assert_eq!(Command::from_str("expire token 60").unwrap(), Command::Expire("token".to_string(), 60));
+    assert!(Command::from_str("expire token soon").is_err());
+  }
+
+  #[test]
+  fn test_convert() {
+    assert_eq!(Command::from_str("convert --to binary").unwrap(), Command::Convert(Format::Binary));
+    assert!(Command::from_str("convert --to yaml").is_err());
+    assert!(Command::from_str("convert binary json").is_err());
+  }
+
+  #[test]
+  fn test_verify_and_repair() {
+    assert_eq!(Command::from_str("verify").unwrap(), Command::Verify);
+    assert_eq!(Command::from_str("repair").unwrap(), Command::Repair(None));
+    assert_eq!(Command::from_str("repair fixed.json").unwrap(), Command::Repair(Some("fixed.json".to_string())));
+    assert!(!Command::Repair(None).is_change());
+  }
+
   #[test]
   fn test_from_vec() {
     let strings = "put bla gna".split(' ').map(|x| x.to_string()).collect();
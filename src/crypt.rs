@@ -0,0 +1,178 @@
+use std::env;
+
+use chacha20poly1305::{
+  ChaCha20Poly1305,
+  Key,
+  Nonce,
+};
+use chacha20poly1305::aead::{
+  Aead,
+  KeyInit,
+  Payload,
+};
+use getrandom;
+use pbkdf2;
+use rpassword;
+use sha2::Sha256;
+
+use ::{
+  KVError,
+  Result,
+};
+
+/// Marks an encrypted store. It is followed by the version of the envelope.
+pub const MAGIC: &[u8] = b"KVS2ENC";
+
+const VERSION: u8 = 1;
+
+/// Environment variable holding the passphrase. Without it, the passphrase is prompted for.
+pub const PASSPHRASE_VAR: &str = "KVS2_PASSPHRASE";
+
+/// PBKDF2-HMAC-SHA256 rounds for new stores. Stores record their own count, so it can be raised later.
+const ITERATIONS: u32 = 200_000;
+
+/// Most PBKDF2 rounds a store may ask for. The count is read before the header can be
+/// authenticated, so a tampered count must not make opening the store take forever.
+const MAX_ITERATIONS: u32 = 50 * ITERATIONS;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 4 + SALT_LEN + NONCE_LEN;
+
+pub fn is_encrypted(bytes: &[u8]) -> bool {
+  bytes.starts_with(MAGIC)
+}
+
+/// Reads the passphrase from `PASSPHRASE_VAR` or the terminal. A new passphrase has to be typed twice.
+pub fn passphrase(new: bool) -> Result<String> {
+  if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+    return Ok(passphrase);
+  }
+
+  let passphrase = rpassword::prompt_password("Passphrase: ")?;
+
+  if new && rpassword::prompt_password("Repeat passphrase: ")? != passphrase {
+    return Err(KVError::UnknownError("passphrases do not match".to_string()));
+  }
+
+  Ok(passphrase)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> [u8; 32] {
+  let mut key = [0; 32];
+  pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+  key
+}
+
+fn random_bytes(buf: &mut [u8]) -> Result<()> {
+  getrandom::getrandom(buf).map_err(|e| KVError::UnknownError(format!("no randomness available: {}", e)))
+}
+
+/// Encrypts a serialized store with a key derived from `passphrase`.
+///
+/// The envelope is the magic bytes, the version, the little endian iteration count, the salt
+/// and the nonce, followed by the ChaCha20-Poly1305 ciphertext. The header is authenticated
+/// along with the ciphertext.
+pub fn seal(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+  seal_with(plaintext, passphrase, ITERATIONS)
+}
+
+fn seal_with(plaintext: &[u8], passphrase: &str, iterations: u32) -> Result<Vec<u8>> {
+  let mut salt = [0; SALT_LEN];
+  let mut nonce = [0; NONCE_LEN];
+  random_bytes(&mut salt)?;
+  random_bytes(&mut nonce)?;
+
+  let mut sealed = Vec::with_capacity(HEADER_LEN + plaintext.len() + 16);
+  sealed.extend_from_slice(MAGIC);
+  sealed.push(VERSION);
+  sealed.extend_from_slice(&iterations.to_le_bytes());
+  sealed.extend_from_slice(&salt);
+  sealed.extend_from_slice(&nonce);
+
+  let key = derive_key(passphrase, &salt, iterations);
+  let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+  let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &sealed })
+    .map_err(|_| KVError::UnknownError("encryption failed".to_string()))?;
+
+  sealed.extend_from_slice(&ciphertext);
+  Ok(sealed)
+}
+
+/// Decrypts a store sealed by `seal`. A wrong passphrase and a modified file cannot be told apart.
+pub fn open(sealed: &[u8], passphrase: &str) -> Result<Vec<u8>> {
+  if !is_encrypted(sealed) || sealed.len() < HEADER_LEN {
+    return Err(KVError::CorruptStore("not an encrypted store".to_string()));
+  }
+
+  let version = sealed[MAGIC.len()];
+  if version != VERSION {
+    return Err(KVError::UnsupportedVersion(u64::from(version), u64::from(VERSION)));
+  }
+
+  let (header, ciphertext) = sealed.split_at(HEADER_LEN);
+  let mut iterations = [0; 4];
+  iterations.copy_from_slice(&header[MAGIC.len() + 1..MAGIC.len() + 5]);
+  let salt = &header[MAGIC.len() + 5..MAGIC.len() + 5 + SALT_LEN];
+  let nonce = &header[HEADER_LEN - NONCE_LEN..];
+
+  let iterations = u32::from_le_bytes(iterations);
+  if !(ITERATIONS..=MAX_ITERATIONS).contains(&iterations) {
+    return Err(KVError::CorruptStore(format!("iteration count {} outside of {} to {}", iterations, ITERATIONS, MAX_ITERATIONS)));
+  }
+
+  let key = derive_key(passphrase, salt, iterations);
+  let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+
+  cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: header })
+    .map_err(|_| KVError::DecryptionFailed)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_round_trip() {
+    let sealed = seal(b"{\"content\": {}}", "secret").unwrap();
+
+    assert!(is_encrypted(&sealed));
+    assert!(!sealed.windows(7).any(|w| w == b"content"));
+    assert_eq!(b"{\"content\": {}}".to_vec(), open(&sealed, "secret").unwrap());
+  }
+
+  #[test]
+  fn test_wrong_passphrase_and_tampering_fail() {
+    let sealed = seal(b"some store", "secret").unwrap();
+
+    match open(&sealed, "guess") {
+      Err(KVError::DecryptionFailed) => (),
+      other => panic!("unexpected result {:?}", other),
+    }
+
+    for &i in &[MAGIC.len() + 1, HEADER_LEN - 1, sealed.len() - 1] {
+      let mut tampered = sealed.clone();
+      tampered[i] ^= 1;
+
+      match open(&tampered, "secret") {
+        Err(KVError::DecryptionFailed) => (),
+        other => panic!("unexpected result for byte {}: {:?}", i, other),
+      }
+    }
+  }
+
+  #[test]
+  fn test_iteration_count_is_bounded() {
+    let sealed = seal(b"some store", "secret").unwrap();
+
+    for &iterations in &[1000, MAX_ITERATIONS + 1, u32::MAX] {
+      let mut tampered = sealed.clone();
+      tampered[MAGIC.len() + 1..MAGIC.len() + 5].copy_from_slice(&iterations.to_le_bytes());
+
+      match open(&tampered, "secret") {
+        Err(KVError::CorruptStore(_)) => (),
+        other => panic!("unexpected result for {} iterations: {:?}", iterations, other),
+      }
+    }
+  }
+}
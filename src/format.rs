@@ -0,0 +1,596 @@
+use std;
+use std::collections::{
+  BTreeSet,
+  HashMap,
+};
+use std::fmt::{
+  Display,
+  Formatter,
+};
+use std::io::Write;
+use std::iter::FromIterator;
+use std::str::FromStr;
+
+use serde_json::{
+  self,
+  Value as JsonValue,
+};
+
+use ::{
+  KVError,
+  Result,
+  Value,
+};
+
+use ::backend::{
+  Backend,
+  MemoryBackend,
+};
+
+use ::verify::{
+  Problem,
+  Report,
+};
+
+/// The encodings a store file can be written in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+  /// Pretty printed JSON, easy to read and diff.
+  Json,
+  /// A compact binary encoding, quicker to read and write for large stores.
+  Binary,
+}
+
+/// Starts every store in the binary format, followed by a byte with the format version.
+/// JSON stores start with `{` or whitespace instead.
+const MAGIC: &[u8] = b"KVS2BIN";
+
+/// Version of the store layout written by this build. Bump it together with a new entry in `MIGRATIONS`.
+pub const FORMAT_VERSION: u64 = 3;
+
+/// Upgrades a JSON store from the version at its index to the next version.
+const MIGRATIONS: [fn(JsonValue) -> Result<JsonValue>; 3] = [
+  from_unversioned,
+  from_v1,
+  from_v2,
+];
+
+const STRING_TAG: u8 = 0;
+const LIST_TAG: u8 = 1;
+const INT_TAG: u8 = 2;
+const MAP_TAG: u8 = 3;
+const SET_TAG: u8 = 4;
+
+impl Format {
+  /// Tells the format of a store from its first bytes.
+  pub fn detect(bytes: &[u8]) -> Format {
+    if bytes.starts_with(MAGIC) {
+      Format::Binary
+    } else {
+      Format::Json
+    }
+  }
+}
+
+impl FromStr for Format {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, String> {
+    match s {
+      "json" => Ok(Format::Json),
+      "binary" => Ok(Format::Binary),
+      other => Err(format!("unknown format: {}", other)),
+    }
+  }
+}
+
+impl Display for Format {
+  fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+    match *self {
+      Format::Json => write!(f, "json"),
+      Format::Binary => write!(f, "binary"),
+    }
+  }
+}
+
+/// Stores written before versioning have no `version` field, but otherwise the layout of version 1.
+fn from_unversioned(store: JsonValue) -> Result<JsonValue> {
+  Ok(store)
+}
+
+/// Version 2 adds an optional checksum, which `read_json` has already checked and removed.
+fn from_v1(store: JsonValue) -> Result<JsonValue> {
+  Ok(store)
+}
+
+/// Version 3 adds the optional set of secret keys.
+fn from_v2(store: JsonValue) -> Result<JsonValue> {
+  Ok(store)
+}
+
+/// CRC-32 (IEEE 802.3) of `bytes`.
+fn crc32(bytes: &[u8]) -> u32 {
+  let mut crc = !0u32;
+
+  for &b in bytes {
+    crc ^= u32::from(b);
+
+    for _ in 0..8 {
+      crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+    }
+  }
+
+  !crc
+}
+
+/// The checksum of a JSON store. It is taken over the compact serialization, which has sorted
+/// keys, so it does not depend on how the file is formatted.
+fn json_checksum(store: &JsonValue) -> Result<String> {
+  Ok(format!("{:08x}", crc32(serde_json::to_string(store)?.as_bytes())))
+}
+
+/// Removes the checksum from `store` and compares it with the content. Stores without a checksum pass.
+fn check_json_checksum(store: &mut JsonValue) -> Result<()> {
+  let stored = match store.as_object_mut().and_then(|store| store.remove("checksum")) {
+    Some(stored) => stored,
+    None => return Ok(()),
+  };
+
+  let stored = stored.as_str().map_or_else(|| stored.to_string(), str::to_string);
+  let actual = json_checksum(store)?;
+
+  if stored == actual {
+    Ok(())
+  } else {
+    Err(KVError::ChecksumMismatch(format!("stored {}, computed {}", stored, actual)))
+  }
+}
+
+fn check_version(version: u64) -> Result<()> {
+  if version > FORMAT_VERSION {
+    Err(KVError::UnsupportedVersion(version, FORMAT_VERSION))
+  } else {
+    Ok(())
+  }
+}
+
+/// Upgrades a JSON store of any older version to `FORMAT_VERSION`, one migration at a time.
+pub fn migrate_json(mut store: JsonValue) -> Result<JsonValue> {
+  let version = match store.get("version") {
+    None => 0,
+    Some(version) => version.as_u64().ok_or(KVError::CorruptStore(format!("invalid version {}", version)))?,
+  };
+
+  check_version(version)?;
+
+  for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+    store = migration(store)?;
+    store.as_object_mut()
+      .ok_or(KVError::CorruptStore("not a JSON object".to_string()))?
+      .insert("version".to_string(), JsonValue::from(from as u64 + 1));
+  }
+
+  Ok(store)
+}
+
+/// Reads a JSON store, checking its checksum and migrating it from older versions.
+pub fn read_json(bytes: &[u8]) -> Result<MemoryBackend> {
+  let mut store = serde_json::from_slice(bytes)?;
+  check_json_checksum(&mut store)?;
+
+  Ok(serde_json::from_value(migrate_json(store)?)?)
+}
+
+/// Writes a JSON store along with its checksum.
+pub fn write_json<W: Write>(memory: &MemoryBackend, w: &mut W) -> Result<()> {
+  let mut store = serde_json::to_value(memory)?;
+  let checksum = json_checksum(&store)?;
+
+  if let Some(store) = store.as_object_mut() {
+    store.insert("checksum".to_string(), JsonValue::String(checksum));
+  }
+
+  Ok(serde_json::to_writer_pretty(w, &store)?)
+}
+
+fn write_varint<W: Write>(w: &mut W, mut n: u64) -> Result<()> {
+  loop {
+    let byte = (n & 0x7f) as u8;
+    n >>= 7;
+
+    if n == 0 {
+      return Ok(w.write_all(&[byte])?);
+    }
+
+    w.write_all(&[byte | 0x80])?;
+  }
+}
+
+fn write_str<W: Write>(w: &mut W, s: &str) -> Result<()> {
+  write_varint(w, s.len() as u64)?;
+  Ok(w.write_all(s.as_bytes())?)
+}
+
+fn write_strs<'a, W: Write, I: ExactSizeIterator<Item = &'a String>>(w: &mut W, strs: I) -> Result<()> {
+  write_varint(w, strs.len() as u64)?;
+
+  for s in strs {
+    write_str(w, s)?;
+  }
+
+  Ok(())
+}
+
+fn write_value<W: Write>(w: &mut W, value: &Value) -> Result<()> {
+  match *value {
+    Value::StringValue(ref s) => {
+      w.write_all(&[STRING_TAG])?;
+      write_str(w, s)
+    },
+    Value::ListValue(ref list) => {
+      w.write_all(&[LIST_TAG])?;
+      write_strs(w, list.iter())
+    },
+    Value::IntValue(i) => {
+      w.write_all(&[INT_TAG])?;
+      Ok(w.write_all(&i.to_le_bytes())?)
+    },
+    Value::MapValue(ref map) => {
+      let mut fields: Vec<(&String, &String)> = map.iter().collect();
+      fields.sort();
+
+      w.write_all(&[MAP_TAG])?;
+      write_varint(w, fields.len() as u64)?;
+
+      for (field, value) in fields {
+        write_str(w, field)?;
+        write_str(w, value)?;
+      }
+
+      Ok(())
+    },
+    Value::SetValue(ref set) => {
+      w.write_all(&[SET_TAG])?;
+      write_strs(w, set.iter())
+    },
+  }
+}
+
+/// Writes the content of `backend` in the binary format.
+///
+/// After the magic bytes and the version come the operation log sequence number, the keys with their values
+/// and the expiry timestamps, followed by the little endian CRC-32 of everything before it.
+/// Numbers are LEB128 varints, except for the little endian values of integers, and strings
+/// are prefixed with their length. Keys are sorted, so the same content always gives the same bytes.
+pub fn write_binary<B: Backend, W: Write>(backend: &B, w: &mut W) -> Result<()> {
+  let mut bytes = Vec::new();
+  encode_binary(backend, &mut bytes)?;
+
+  let checksum = crc32(&bytes);
+  bytes.extend_from_slice(&checksum.to_le_bytes());
+
+  Ok(w.write_all(&bytes)?)
+}
+
+fn encode_binary<B: Backend, W: Write>(backend: &B, w: &mut W) -> Result<()> {
+  w.write_all(MAGIC)?;
+  w.write_all(&[FORMAT_VERSION as u8])?;
+  write_varint(w, backend.log_seq())?;
+
+  let mut keys = backend.keys();
+  keys.sort();
+
+  write_varint(w, keys.len() as u64)?;
+  for key in keys {
+    write_str(w, key)?;
+    write_value(w, backend.get(key).unwrap())?;
+  }
+
+  let mut expires: Vec<(&String, &u64)> = backend.expires().iter().collect();
+  expires.sort();
+
+  write_varint(w, expires.len() as u64)?;
+  for (key, &expires_at) in expires {
+    write_str(w, key)?;
+    write_varint(w, expires_at)?;
+  }
+
+  write_strs(w, backend.secrets().iter())
+}
+
+/// Strips the checksum trailer of a binary store of `version` 2 or later, checking it against the rest.
+fn checked_body(bytes: &[u8], version: u64) -> Result<&[u8]> {
+  if version < 2 {
+    return Ok(bytes);
+  }
+
+  if bytes.len() < MAGIC.len() + 1 + 4 {
+    return Err(KVError::CorruptStore("unexpected end of file".to_string()));
+  }
+
+  let (body, trailer) = bytes.split_at(bytes.len() - 4);
+  let mut stored = [0; 4];
+  stored.copy_from_slice(trailer);
+
+  let stored = u32::from_le_bytes(stored);
+  let actual = crc32(body);
+
+  if stored != actual {
+    return Err(KVError::ChecksumMismatch(format!("stored {:08x}, computed {:08x}", stored, actual)));
+  }
+
+  Ok(body)
+}
+
+fn binary_version(bytes: &[u8]) -> Result<u64> {
+  match bytes.get(MAGIC.len()) {
+    Some(&version) if bytes.starts_with(MAGIC) => Ok(u64::from(version)),
+    _ => Err(KVError::CorruptStore("not a binary store".to_string())),
+  }
+}
+
+type DecodeResult<T> = std::result::Result<T, Problem>;
+
+struct Decoder<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+  key: Option<String>,
+  version: u64,
+}
+
+impl<'a> Decoder<'a> {
+  fn error<S: ToString>(&self, msg: S) -> Problem {
+    Problem {
+      offset: self.pos,
+      key: self.key.clone(),
+      message: msg.to_string(),
+    }
+  }
+
+  fn take(&mut self, n: usize) -> DecodeResult<&'a [u8]> {
+    if self.bytes.len() - self.pos < n {
+      return Err(self.error("unexpected end of file"));
+    }
+
+    let taken = &self.bytes[self.pos..self.pos + n];
+    self.pos += n;
+    Ok(taken)
+  }
+
+  fn varint(&mut self) -> DecodeResult<u64> {
+    let mut n = 0u64;
+
+    for shift in (0..64).step_by(7) {
+      let byte = self.take(1)?[0];
+      n |= u64::from(byte & 0x7f) << shift;
+
+      if byte & 0x80 == 0 {
+        return Ok(n);
+      }
+    }
+
+    Err(self.error("varint too long"))
+  }
+
+  /// Reads a length, which can never exceed the number of remaining bytes.
+  fn len(&mut self) -> DecodeResult<usize> {
+    let len = self.varint()?;
+
+    if len > (self.bytes.len() - self.pos) as u64 {
+      return Err(self.error(format!("length {} exceeds the file", len)));
+    }
+
+    Ok(len as usize)
+  }
+
+  fn string(&mut self) -> DecodeResult<String> {
+    let len = self.len()?;
+    let bytes = self.take(len)?;
+
+    String::from_utf8(bytes.to_vec()).map_err(|_| self.error("invalid utf-8"))
+  }
+
+  fn strings<C: FromIterator<String>>(&mut self) -> DecodeResult<C> {
+    let len = self.len()?;
+    (0..len).map(|_| self.string()).collect()
+  }
+
+  fn value(&mut self) -> DecodeResult<Value> {
+    match self.take(1)?[0] {
+      STRING_TAG => Ok(Value::StringValue(self.string()?)),
+      LIST_TAG => Ok(Value::ListValue(self.strings()?)),
+      INT_TAG => {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(Value::IntValue(i64::from_le_bytes(bytes)))
+      },
+      MAP_TAG => {
+        let len = self.len()?;
+        let map = (0..len).map(|_| Ok((self.string()?, self.string()?))).collect::<DecodeResult<HashMap<String, String>>>()?;
+        Ok(Value::MapValue(map))
+      },
+      SET_TAG => Ok(Value::SetValue(self.strings::<BTreeSet<String>>()?)),
+      tag => Err(self.error(format!("unknown value type {}", tag))),
+    }
+  }
+
+  /// Decodes everything after the header into `memory`, stopping at the first problem.
+  fn decode(&mut self, memory: &mut MemoryBackend) -> DecodeResult<()> {
+    memory.set_log_seq(self.varint()?);
+
+    for _ in 0..self.len()? {
+      let key = self.string()?;
+      self.key = Some(key.clone());
+
+      let value = self.value()?;
+      memory.put(key, value);
+    }
+
+    self.key = None;
+
+    for _ in 0..self.len()? {
+      let key = self.string()?;
+      let expires_at = self.varint()?;
+      memory.expires_mut().insert(key, expires_at);
+    }
+
+    if self.version >= 3 {
+      let secrets: Vec<String> = self.strings()?;
+      memory.secrets_mut().extend(secrets);
+    }
+
+    if self.pos != self.bytes.len() {
+      return Err(self.error("trailing data"));
+    }
+
+    Ok(())
+  }
+}
+
+/// Reads a store written by `write_binary`.
+///
+/// Version 0 stores, written before versioning, have a zero byte in place of the version and
+/// the layout of version 1. Version 2 adds the checksum trailer and version 3 the secret keys.
+pub fn read_binary(bytes: &[u8]) -> Result<MemoryBackend> {
+  let version = binary_version(bytes)?;
+  check_version(version)?;
+
+  let body = checked_body(bytes, version)?;
+  let mut decoder = Decoder { bytes: body, pos: MAGIC.len() + 1, key: None, version: version };
+  let mut memory = MemoryBackend::new();
+
+  decoder.decode(&mut memory).map_err(|problem| KVError::CorruptStore(problem.to_string()))?;
+
+  Ok(memory)
+}
+
+/// Reads as much of a damaged binary store as possible, for `verify`.
+pub fn salvage_binary(bytes: &[u8]) -> Report {
+  let mut problems = Vec::new();
+  let mut salvaged = MemoryBackend::new();
+
+  let header_problem = |e: KVError| Problem { offset: MAGIC.len(), key: None, message: e.to_string() };
+
+  match binary_version(bytes).and_then(|version| check_version(version).map(|_| version)) {
+    Ok(version) => {
+      let body = match checked_body(bytes, version) {
+        Ok(body) => body,
+        Err(e) => {
+          problems.push(Problem { offset: bytes.len().saturating_sub(4), key: None, message: e.to_string() });
+          &bytes[..bytes.len().saturating_sub(4).max(MAGIC.len() + 1)]
+        },
+      };
+
+      let mut decoder = Decoder { bytes: body, pos: MAGIC.len() + 1, key: None, version: version };
+      if let Err(problem) = decoder.decode(&mut salvaged) {
+        problems.push(problem);
+      }
+    },
+    Err(e) => problems.push(header_problem(e)),
+  }
+
+  Report {
+    problems: problems,
+    salvaged: salvaged,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use ::KVStore;
+
+  fn sample_store() -> KVStore<MemoryBackend> {
+    let mut kvs = KVStore::new();
+    kvs.put("string", "välue");
+    kvs.put_empty_list("list");
+    kvs.push_value("list", "a").unwrap();
+    kvs.push_value("list", "").unwrap();
+    kvs.increment("int", -300).unwrap();
+    kvs.put_map_value("map", "field", "value").unwrap();
+    kvs.add_set_member("set", "member").unwrap();
+    kvs.expire_at("string", u64::MAX);
+    kvs.backend_mut().set_log_seq(42);
+    kvs
+  }
+
+  #[test]
+  fn test_binary_round_trip() {
+    let mut kvs = sample_store();
+    kvs.put_secret("password", "hunter2");
+
+    let mut bytes = Vec::new();
+    kvs.write_format(&mut bytes, Format::Binary).unwrap();
+    assert_eq!(Format::Binary, Format::detect(&bytes));
+
+    let read = KVStore::read(&bytes[..]).unwrap();
+
+    for key in kvs.get_keys() {
+      assert_eq!(kvs.get(key), read.get(key));
+    }
+    assert_eq!(kvs.get_keys().len(), read.get_keys().len());
+    assert_eq!(kvs.backend().expires(), read.backend().expires());
+    assert!(read.is_secret("password"));
+    assert_eq!(42, read.backend().log_seq());
+
+    let mut again = Vec::new();
+    read.write_format(&mut again, Format::Binary).unwrap();
+    assert_eq!(bytes, again);
+  }
+
+  #[test]
+  fn test_json_is_detected() {
+    let mut bytes = Vec::new();
+    sample_store().write(&mut bytes).unwrap();
+
+    assert_eq!(Format::Json, Format::detect(&bytes));
+    assert!(KVStore::read(&bytes[..]).unwrap().has_key("list"));
+  }
+
+  #[test]
+  fn test_migrates_unversioned_json() {
+    let legacy = r#"{"content": {"key": {"StringValue": "value"}}}"#;
+    let kvs = KVStore::read(legacy.as_bytes()).unwrap();
+    assert!(kvs.has_key("key"));
+
+    let mut bytes = Vec::new();
+    kvs.write(&mut bytes).unwrap();
+    let written: JsonValue = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(Some(FORMAT_VERSION), written.get("version").and_then(JsonValue::as_u64));
+  }
+
+  #[test]
+  fn test_newer_version_fails() {
+    let newer = format!(r#"{{"version": {}, "content": {{}}}}"#, FORMAT_VERSION + 1);
+
+    match KVStore::read(newer.as_bytes()) {
+      Err(KVError::UnsupportedVersion(version, FORMAT_VERSION)) => assert_eq!(FORMAT_VERSION + 1, version),
+      other => panic!("unexpected result {:?}", other),
+    }
+
+    let mut bytes = Vec::new();
+    sample_store().write_format(&mut bytes, Format::Binary).unwrap();
+
+    bytes[MAGIC.len()] = FORMAT_VERSION as u8 + 1;
+    assert!(matches!(read_binary(&bytes), Err(KVError::UnsupportedVersion(..))));
+
+    // version 0 stores have neither the empty set of secrets nor the checksum trailer
+    bytes[MAGIC.len()] = 0;
+    let len = bytes.len() - 5;
+    assert!(read_binary(&bytes[..len]).unwrap().get("list").is_some());
+  }
+
+  #[test]
+  fn test_truncated_binary_fails() {
+    let mut bytes = Vec::new();
+    sample_store().write_format(&mut bytes, Format::Binary).unwrap();
+
+    for len in MAGIC.len() + 1..bytes.len() {
+      match read_binary(&bytes[..len]) {
+        Err(KVError::CorruptStore(_)) | Err(KVError::ChecksumMismatch(_)) => (),
+        other => panic!("unexpected result {:?}", other),
+      }
+    }
+  }
+}
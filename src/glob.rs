@@ -0,0 +1,49 @@
+/// Matches `text` against a glob `pattern`, where `*` matches any sequence of characters
+/// and `?` matches a single character.
+pub fn matches(pattern: &str, text: &str) -> bool {
+  let pattern: Vec<char> = pattern.chars().collect();
+  let text: Vec<char> = text.chars().collect();
+
+  let (mut p, mut t) = (0, 0);
+  let mut backtrack: Option<(usize, usize)> = None;
+
+  while t < text.len() {
+    if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+      p += 1;
+      t += 1;
+    } else if p < pattern.len() && pattern[p] == '*' {
+      backtrack = Some((p, t));
+      p += 1;
+    } else if let Some((star_p, star_t)) = backtrack {
+      p = star_p + 1;
+      t = star_t + 1;
+      backtrack = Some((star_p, star_t + 1));
+    } else {
+      return false;
+    }
+  }
+
+  pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_literal() {
+    assert!(matches("key", "key"));
+    assert!(!matches("key", "keys"));
+    assert!(!matches("keys", "key"));
+  }
+
+  #[test]
+  fn test_wildcards() {
+    assert!(matches("*", ""));
+    assert!(matches("deploy.*", "deploy.web1"));
+    assert!(matches("*.ip", "host.web1.ip"));
+    assert!(matches("h?st.*.ip", "host.web1.ip"));
+    assert!(!matches("deploy.*", "secrets/deploy"));
+    assert!(!matches("h?st", "hst"));
+  }
+}
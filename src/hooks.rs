@@ -1,6 +1,21 @@
 
-use ::ui::UiResult;
+use ::ui::{
+    UiError,
+    UiResult,
+    SECRET_MASK,
+};
 use ::cmd::Command as Cmd;
+use ::listener::{
+    ChangeEvent,
+    ChangeListener,
+};
+use ::glob;
+use ::Value;
+
+use serde_json::{
+    self,
+    Value as JsonValue,
+};
 
 use std::path::{
     Path,
@@ -8,45 +23,369 @@ use std::path::{
 };
 
 use std::process::{
+    Child,
     Command,
+    ExitStatus,
     Stdio,
 };
 
 use std::io::{
+    self,
     BufWriter,
+    Read,
     Write,
 };
 
+use std::thread;
+
+use std::time::{
+    Duration,
+    Instant,
+};
+
 use std::fs;
 
+use std::fmt::{
+    self,
+    Display,
+    Formatter,
+};
+
 #[derive(Debug)]
 pub struct Hooks {
-    post_change: Option<PathBuf>
+    pre_change: Vec<Hook>,
+    post_change: Vec<Hook>,
+    on_miss: Vec<Hook>,
+    stop_on_failure: bool,
+    json_events: bool,
+    timeout: Option<Duration>,
+}
+
+/// Binds hook scripts to keys and actions, in addition to the `.preChange`, `.postChange` and
+/// `.onMiss` hooks that run for every command. Read from the hooks directory, for example:
+///
+/// ```json
+/// {"postChange": [{"run": "scripts/deploy", "keys": ["deploy.*"], "actions": ["put", "drop"]}]}
+/// ```
+pub const CONFIG_FILE: &str = ".hooks.json";
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct HookConfig {
+    #[serde(rename = "preChange", default)]
+    pre_change: Vec<Hook>,
+    #[serde(rename = "postChange", default)]
+    post_change: Vec<Hook>,
+    #[serde(rename = "onMiss", default)]
+    on_miss: Vec<Hook>,
+}
+
+/// A hook script, run only for the keys matching one of the glob patterns in `keys` and for the
+/// `actions` named like in the hook arguments. Empty lists match everything.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub run: PathBuf,
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub actions: Vec<String>,
+}
+
+impl Hook {
+    /// A hook run for every key and action.
+    pub fn new(run: PathBuf) -> Hook {
+        Hook {
+            run: run,
+            keys: Vec::new(),
+            actions: Vec::new(),
+        }
+    }
+
+    /// Whether the hook runs for `action` on `key`. A hook bound to keys does not run for commands without one.
+    pub fn matches(&self, action: &str, key: Option<&str>) -> bool {
+        let action_matches = self.actions.is_empty() || self.actions.iter().any(|a| a == action);
+        let key_matches = self.keys.is_empty() || key.is_some_and(|key| self.keys.iter().any(|pattern| glob::matches(pattern, key)));
+
+        action_matches && key_matches
+    }
+}
+
+/// Which output of a hook is captured; the rest goes to our own stdout and stderr.
+#[derive(Clone, Copy)]
+enum Capture {
+    Stdout,
+    Stderr,
+    Nothing,
+}
+
+/// How long a hook may run before it is killed, unless `set_timeout` says otherwise.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum HookError {
+    SpawnError(PathBuf, io::Error),
+    IoError(PathBuf, io::Error),
+    TimedOut(PathBuf, Duration),
+    EncodingError(serde_json::Error),
+    ConfigError(PathBuf, String),
+}
+
+impl Display for HookError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match *self {
+            HookError::SpawnError(ref hook, ref e) => write!(f, "could not run hook {}: {}", hook.display(), e),
+            HookError::IoError(ref hook, ref e) => write!(f, "could not talk to hook {}: {}", hook.display(), e),
+            HookError::TimedOut(ref hook, timeout) => write!(f, "hook {} was killed after running for {}s", hook.display(), timeout.as_secs()),
+            HookError::EncodingError(ref e) => write!(f, "could not encode the hook event: {}", e),
+            HookError::ConfigError(ref path, ref e) => write!(f, "invalid hook config {}: {}", path.display(), e),
+        }
+    }
 }
 
-fn get_hook_str<'a>(command: &'a Cmd) -> (&'a str, Option<&'a str>, Option<&'a str>) {
+/// What a hook receives on stdin, as a single line of JSON, if `set_json_events` is set.
+///
+/// Values are plain JSON with `value_type` naming their type. `previous_value` is the value at
+/// `key` before the command and `new_value` the one after it, which is not known yet to the
+/// `.preChange` hooks. The values of secret keys are masked.
+#[derive(Serialize, Debug)]
+pub struct HookEvent {
+    pub command: String,
+    pub key: Option<String>,
+    pub value: Vec<String>,
+    pub previous_value: Option<JsonValue>,
+    pub new_value: Option<JsonValue>,
+    pub value_type: Option<String>,
+    pub store: String,
+    pub timestamp: u64,
+}
+
+impl HookEvent {
+    /// Starts the event for `command` on the store at `store`, without any values yet.
+    pub fn new(command: &Cmd, store: &str) -> HookEvent {
+        let (action, key, values) = get_hook_str(command);
+
+        HookEvent {
+            command: action.to_string(),
+            key: key.map(str::to_string),
+            value: values,
+            previous_value: None,
+            new_value: None,
+            value_type: None,
+            store: store.to_string(),
+            timestamp: ::unix_now(),
+        }
+    }
+
+    /// Describes `change` for the hooks, masking the values of secret keys.
+    pub fn from_change(change: &ChangeEvent) -> HookEvent {
+        let mut event = HookEvent::new(&change.command, &change.store);
+        event.previous_value = change.old_value.as_ref().map(|value| HookEvent::json(value, change.secret));
+        event.new_value = change.new_value.as_ref().map(|value| HookEvent::json(value, change.secret));
+        event.value_type = change.new_value.as_ref().or(change.old_value.as_ref()).map(|value| value.get_type().name().to_string());
+        event.timestamp = change.timestamp;
+        event
+    }
+
+    fn json(value: &Value, secret: bool) -> JsonValue {
+        if secret {
+            return JsonValue::String(SECRET_MASK.to_string());
+        }
+
+        value.to_json().unwrap_or(JsonValue::Null)
+    }
+}
+
+/// The exit status of one hook, `None` if it was killed by a signal or did not run to its end.
+#[derive(Debug, PartialEq, Eq)]
+pub struct HookStatus {
+    pub hook: PathBuf,
+    pub code: Option<i32>,
+    /// Why the hook could not be run or was killed, like a timeout.
+    pub error: Option<String>,
+}
+
+fn get_hook_str<'a>(command: &'a Cmd) -> (&'a str, Option<&'a str>, Vec<String>) {
     match *command {
-        Cmd::Init => ("init", None, None),
-        Cmd::PutString(ref key, ref val) => ("put", Some(key.as_str()), Some(val.as_str())),
-        Cmd::Drop(ref key) => ("drop", Some(key.as_str()), None),
-        Cmd::CreateEmptyList(ref key) => ("emptyList", Some(key.as_str()), None),
-        Cmd::PushListValue(ref key, ref val) => ("push", Some(key.as_str()), Some(val.as_str())),
-        Cmd::PopListValue(ref key) => ("pop", Some(key.as_str()), None),
-        Cmd::ClearList(ref key) => ("clear", Some(key.as_str()), None),
+        Cmd::Init(_) => ("init", None, vec![]),
+        Cmd::Get(ref key) => ("get", Some(key.as_str()), vec![]),
+        Cmd::PutString(ref key, ref val) => ("put", Some(key.as_str()), vec![val.clone()]),
+        Cmd::PutSecret(ref key, _) => ("put", Some(key.as_str()), vec![SECRET_MASK.to_string()]),
+        Cmd::PutStringWithTtl(ref key, ref val, ttl) => ("put", Some(key.as_str()), vec![val.clone(), ttl.to_string()]),
+        Cmd::Drop(ref key) => ("drop", Some(key.as_str()), vec![]),
+        Cmd::Expire(ref key, ttl) => ("expire", Some(key.as_str()), vec![ttl.to_string()]),
+        Cmd::Persist(ref key) => ("persist", Some(key.as_str()), vec![]),
+        Cmd::Purge => ("purge", None, vec![]),
+        Cmd::Compact => ("compact", None, vec![]),
+        Cmd::Convert(format) => ("convert", None, vec![format.to_string()]),
+        Cmd::CreateEmptyList(ref key) => ("emptyList", Some(key.as_str()), vec![]),
+        Cmd::PushListValue(ref key, ref val) => ("push", Some(key.as_str()), vec![val.clone()]),
+        Cmd::PushListValues(ref key, ref vals) => ("push", Some(key.as_str()), vals.clone()),
+        Cmd::PushFrontListValues(ref key, ref vals) => ("pushFront", Some(key.as_str()), vals.clone()),
+        Cmd::PopListValue(ref key) => ("pop", Some(key.as_str()), vec![]),
+        Cmd::ClearList(ref key) => ("clear", Some(key.as_str()), vec![]),
+        Cmd::Increment(ref key, by) => ("incr", Some(key.as_str()), vec![by.to_string()]),
+        Cmd::Decrement(ref key, by) => ("decr", Some(key.as_str()), vec![by.to_string()]),
+        Cmd::MapSet(ref key, ref field, ref val) => ("hset", Some(key.as_str()), vec![field.clone(), val.clone()]),
+        Cmd::MapDelete(ref key, ref field) => ("hdel", Some(key.as_str()), vec![field.clone()]),
+        Cmd::SetAdd(ref key, ref member) => ("sadd", Some(key.as_str()), vec![member.clone()]),
+        Cmd::SetRemove(ref key, ref member) => ("srem", Some(key.as_str()), vec![member.clone()]),
         _ => panic!("not a write operation"),
     }
 }
 
+impl HookStatus {
+    pub fn success(&self) -> bool {
+        self.error.is_none() && self.code == Some(0)
+    }
+}
+
+impl Display for HookStatus {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(ref error) = self.error {
+            return write!(f, "{}", error);
+        }
+
+        match self.code {
+            Some(code) => write!(f, "hook {} exited with status {}", self.hook.display(), code),
+            None => write!(f, "hook {} was killed by a signal", self.hook.display()),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &fs::Metadata) -> bool {
+    true
+}
+
+/// Starts the hook in a process group of its own, so that `kill_hook` reaches whatever it spawned.
+#[cfg(unix)]
+fn isolate(cmd_builder: &mut Command) {
+    use std::os::unix::process::CommandExt;
+
+    cmd_builder.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn isolate(_: &mut Command) {
+}
+
+#[cfg(unix)]
+fn kill_hook(hook_child: &mut Child) {
+    // the group of the hook has its pid, see `isolate`
+    unsafe {
+        libc::kill(-(hook_child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(not(unix))]
+fn kill_hook(hook_child: &mut Child) {
+    let _ = hook_child.kill();
+}
+
 impl Hooks {
-    pub fn load_from_dir<P: AsRef<Path>>(p: P) -> Hooks {
+    /// Finds the hooks in the directory `p`, followed by the ones bound in its `CONFIG_FILE`.
+    pub fn load_from_dir<P: AsRef<Path>>(p: P) -> Result<Hooks, HookError> {
         let path = p.as_ref();
-        let post_change_hook_path = path.join(".postChange");
-        
-        let post_change: Option<PathBuf> = Hooks::get_hook(post_change_hook_path);
+        let config = Hooks::read_config(path)?;
+
+        let with_bound = |hooks: Vec<PathBuf>, bound: Vec<Hook>| {
+            hooks.into_iter().map(Hook::new).chain(bound.into_iter().map(|mut hook| {
+                hook.run = path.join(&hook.run);
+                hook
+            })).collect()
+        };
+
+        Ok(Hooks {
+            pre_change: with_bound(Hooks::get_hooks(path, ".preChange"), config.pre_change),
+            post_change: with_bound(Hooks::get_hooks(path, ".postChange"), config.post_change),
+            on_miss: with_bound(Hooks::get_hooks(path, ".onMiss"), config.on_miss),
+            stop_on_failure: false,
+            json_events: false,
+            timeout: Some(DEFAULT_TIMEOUT),
+        })
+    }
+
+    fn read_config(path: &Path) -> Result<HookConfig, HookError> {
+        let config_path = path.join(CONFIG_FILE);
+
+        match fs::read(&config_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| HookError::ConfigError(config_path, e.to_string())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(HookConfig::default()),
+            Err(e) => Err(HookError::ConfigError(config_path, e.to_string())),
+        }
+    }
+
+    /// The hooks that would run for `command`, in the order they would run, along with the name of
+    /// the stage they run in.
+    pub fn matching(&self, command: &Cmd) -> Vec<(&'static str, &Hook)> {
+        let mut hooks = Vec::new();
+
+        if command.is_change() {
+            let (action, key, _) = get_hook_str(command);
+            hooks.extend(self.pre_change.iter().filter(|hook| hook.matches(action, key)).map(|hook| ("preChange", hook)));
+            hooks.extend(self.post_change.iter().filter(|hook| hook.matches(action, key)).map(|hook| ("postChange", hook)));
+        }
+
+        if let Cmd::Get(_) | Cmd::GetRevealed(_) | Cmd::GetCached(_) = *command {
+            hooks.extend(self.on_miss.iter().filter(|hook| hook.matches("get", command.key())).map(|hook| ("onMiss", hook)));
+        }
+
+        hooks
+    }
 
-        Hooks {
-            post_change: post_change
+    /// Kills hooks that run longer than `timeout`, or lets them run for as long as they like if it is `None`.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Whether there are onMiss hooks to ask for `key`.
+    pub fn has_on_miss(&self, key: &str) -> bool {
+        self.on_miss.iter().any(|hook| hook.matches("get", Some(key)))
+    }
+
+    /// Whether there are no hooks to run for changes.
+    pub fn is_empty(&self) -> bool {
+        self.pre_change.is_empty() && self.post_change.is_empty()
+    }
+
+    /// Passes a `HookEvent` to the hooks on stdin instead of the result of the command.
+    pub fn set_json_events(&mut self, json_events: bool) {
+        self.json_events = json_events;
+    }
+
+    pub fn json_events(&self) -> bool {
+        self.json_events
+    }
+
+    /// Stops running the post change hooks at the first one that fails.
+    pub fn set_stop_on_failure(&mut self, stop_on_failure: bool) {
+        self.stop_on_failure = stop_on_failure;
+    }
+
+    /// Finds the hook file `name` followed by the executables in the directory `name.d`, in lexical order.
+    fn get_hooks(path: &Path, name: &str) -> Vec<PathBuf> {
+        let mut hooks: Vec<PathBuf> = Hooks::get_hook(path.join(name)).into_iter().collect();
+
+        if let Ok(entries) = fs::read_dir(path.join(format!("{}.d", name))) {
+            let mut dir_hooks: Vec<PathBuf> = entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.metadata().map(|m| m.is_file() && is_executable(&m)).unwrap_or(false))
+                .map(|entry| entry.path())
+                .collect();
+
+            dir_hooks.sort();
+            hooks.extend(dir_hooks);
         }
+
+        hooks
     }
 
     fn get_hook(path_buf: PathBuf) -> Option<PathBuf> {
@@ -65,83 +404,425 @@ impl Hooks {
         }
     }
 
-    pub fn run_post_hooks(&self, result: &UiResult, command: &Cmd) -> Result<bool, String> {
+    /// Prepares to run `hook` with the action, key and values of `command` as arguments, and
+    /// the `KVS2_*` environment variables taken from `event`.
+    fn hook_command(hook: &Path, command: &Cmd, event: Option<&HookEvent>) -> Result<Command, HookError> {
+        let (action, key_opt, values) = get_hook_str(command);
+
+        let canon_hook = fs::canonicalize(hook).map_err(|e| HookError::SpawnError(hook.to_path_buf(), e))?;
+
+        let mut cmd_builder = Command::new(canon_hook);
+        cmd_builder.arg(action);
+
+        if let Some(key) = key_opt {
+            cmd_builder.arg(key);
+        }
+
+        cmd_builder.args(&values);
+
+        cmd_builder.env("KVS2_ACTION", action);
+
+        if let Some(key) = key_opt {
+            cmd_builder.env("KVS2_KEY", key);
+        }
+
+        if let Some(event) = event {
+            cmd_builder.env("KVS2_STORE", &event.store);
+
+            if let Some(ref value_type) = event.value_type {
+                cmd_builder.env("KVS2_VALUE_TYPE", value_type);
+            }
+        }
+
+        Ok(cmd_builder)
+    }
+
+    /// What the hooks read on stdin: the event if `set_json_events` is set, otherwise `fallback`.
+    fn input(&self, event: Option<&HookEvent>, fallback: Option<String>) -> Result<Option<String>, HookError> {
+        match event {
+            Some(event) if self.json_events => Ok(Some(serde_json::to_string(event).map_err(HookError::EncodingError)?)),
+            _ => Ok(fallback),
+        }
+    }
+
+    /// Runs `hook` with `input` on stdin and waits for it, at most for the timeout.
+    /// Returns its exit status and the output chosen by `capture`.
+    fn run_hook(&self, hook: &Path, command: &Cmd, event: Option<&HookEvent>, input: Option<&str>, capture: Capture) -> Result<(ExitStatus, String), HookError> {
+        let mut cmd_builder = Hooks::hook_command(hook, command, event)?;
+        cmd_builder.stdin(if input.is_some() { Stdio::piped() } else { Stdio::null() });
+        isolate(&mut cmd_builder);
+
+        match capture {
+            Capture::Stdout => { cmd_builder.stdout(Stdio::piped()); },
+            Capture::Stderr => { cmd_builder.stderr(Stdio::piped()); },
+            Capture::Nothing => (),
+        }
+
+        let mut hook_child = cmd_builder.spawn().map_err(|e| HookError::SpawnError(hook.to_path_buf(), e))?;
+
+        // written and read in the background, so that a hook ignoring its pipes cannot block us
+        let writer = match (hook_child.stdin.take(), input) {
+            (Some(stdin), Some(input)) => {
+                let input = format!("{}\n", input);
+                Some(thread::spawn(move || BufWriter::new(stdin).write_all(input.as_bytes())))
+            },
+            _ => None,
+        };
+
+        let captured: Option<Box<dyn Read + Send>> = match capture {
+            Capture::Stdout => hook_child.stdout.take().map(|out| Box::new(out) as Box<dyn Read + Send>),
+            Capture::Stderr => hook_child.stderr.take().map(|err| Box::new(err) as Box<dyn Read + Send>),
+            Capture::Nothing => None,
+        };
+
+        let reader = captured.map(|mut captured| thread::spawn(move || {
+            let mut output = String::new();
+            captured.read_to_string(&mut output).map(|_| output)
+        }));
+
+        let status = self.wait(hook, &mut hook_child)?;
+
+        if let Some(Ok(Err(e))) = writer.map(|w| w.join()) {
+            if e.kind() != io::ErrorKind::BrokenPipe {
+                return Err(HookError::IoError(hook.to_path_buf(), e));
+            }
+        }
+
+        let output = match reader.map(|r| r.join()) {
+            Some(Ok(Ok(output))) => output,
+            Some(Ok(Err(e))) => return Err(HookError::IoError(hook.to_path_buf(), e)),
+            _ => String::new(),
+        };
+
+        Ok((status, output))
+    }
+
+    /// Waits for `hook_child`, killing it and its process group once the timeout has run out.
+    fn wait(&self, hook: &Path, hook_child: &mut Child) -> Result<ExitStatus, HookError> {
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return hook_child.wait().map_err(|e| HookError::IoError(hook.to_path_buf(), e)),
+        };
+
+        let start = Instant::now();
+
+        loop {
+            if let Some(status) = hook_child.try_wait().map_err(|e| HookError::IoError(hook.to_path_buf(), e))? {
+                return Ok(status);
+            }
+
+            if start.elapsed() >= timeout {
+                kill_hook(hook_child);
+                let _ = hook_child.wait();
+                return Err(HookError::TimedOut(hook.to_path_buf(), timeout));
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Runs the `.preChange` hooks before `command` is applied. Returns the stderr of the first
+    /// hook that rejected the command by exiting with a non-zero status; later hooks are not run.
+    pub fn run_pre_hooks(&self, command: &Cmd, event: Option<&HookEvent>) -> Result<Option<String>, HookError> {
         if command.is_change() {
-            return self.run_post_change_hook(result, command);
+            return self.run_pre_change_hook(command, event);
         }
 
-        Ok(false)
+        Ok(None)
     }
 
-    pub fn run_post_change_hook(&self, result: &UiResult, command: &Cmd) -> Result<bool, String> {
+    pub fn run_pre_change_hook(&self, command: &Cmd, event: Option<&HookEvent>) -> Result<Option<String>, HookError> {
         assert!(command.is_change());
 
-        match self.post_change {
-            Some(ref post_change_hook) => {
+        let input = self.input(event, None)?;
 
-                let (action, key_opt, val_opt) = get_hook_str(command);
+        let (action, key, _) = get_hook_str(command);
 
-                let canon_hook = fs::canonicalize(post_change_hook).map_err(|e| e.to_string())?;
+        for pre_change_hook in self.pre_change.iter().filter(|hook| hook.matches(action, key)) {
+            let (status, stderr) = self.run_hook(&pre_change_hook.run, command, event, input.as_deref(), Capture::Stderr)?;
 
-                let mut cmd_builder = Command::new(canon_hook);
-                cmd_builder.stdin(Stdio::piped());
-                cmd_builder.arg(action);
-                
-                if let Some(key) = key_opt {
-                    cmd_builder.arg(key);
-                }
+            if !status.success() {
+                return Ok(Some(stderr.trim_end().to_string()));
+            }
+        }
 
-                if let Some(val) = val_opt {
-                    cmd_builder.arg(val);
-                }
+        Ok(None)
+    }
+
+    /// Runs the `.postChange` hooks after `command` was applied and returns the status of each
+    /// hook that ran. A failing hook, including one that could not be started or timed out, does
+    /// not stop the others, unless `set_stop_on_failure` is set.
+    pub fn run_post_hooks(&self, result: &UiResult, command: &Cmd, event: Option<&HookEvent>) -> Result<Vec<HookStatus>, HookError> {
+        if command.is_change() {
+            return self.run_post_change_hook(result, command, event);
+        }
+
+        Ok(Vec::new())
+    }
+
+    pub fn run_post_change_hook(&self, result: &UiResult, command: &Cmd, event: Option<&HookEvent>) -> Result<Vec<HookStatus>, HookError> {
+        assert!(command.is_change());
 
-                let mut hook_child = cmd_builder.spawn().unwrap();
-                {
-                    let stdin = hook_child.stdin.as_mut().ok_or("no stdin".to_string())?;
-                    let mut stdin_buf = BufWriter::new(stdin);
-                    write!(stdin_buf, "{}\n", result).map_err(|e| e.to_string())?;
+        let input = self.input(event, Some(result.to_string()))?;
+
+        let mut statuses = Vec::new();
+
+        let (action, key, _) = get_hook_str(command);
+
+        for post_change_hook in self.post_change.iter().filter(|hook| hook.matches(action, key)) {
+            let hook_status = match self.run_hook(&post_change_hook.run, command, event, input.as_deref(), Capture::Nothing) {
+                Ok((status, _)) => HookStatus {
+                    hook: post_change_hook.run.clone(),
+                    code: status.code(),
+                    error: None,
+                },
+                Err(e) => HookStatus {
+                    hook: post_change_hook.run.clone(),
+                    code: None,
+                    error: Some(e.to_string()),
+                },
+            };
+            let failed = !hook_status.success();
+            statuses.push(hook_status);
+
+            if failed && self.stop_on_failure {
+                break;
+            }
+        }
+
+        Ok(statuses)
+    }
+
+    /// Runs the `.onMiss` hooks for a `key` that is not in `store`. The stdout of the first hook
+    /// that exits successfully is the value, without its final line break. Returns `None` if no
+    /// hook provided a value.
+    pub fn run_on_miss_hooks(&self, key: &str, store: &str) -> Result<Option<String>, HookError> {
+        let command = Cmd::Get(key.to_string());
+        let event = HookEvent::new(&command, store);
+
+        for on_miss_hook in self.on_miss.iter().filter(|hook| hook.matches("get", Some(key))) {
+            let (status, mut stdout) = self.run_hook(&on_miss_hook.run, &command, Some(&event), None, Capture::Stdout)?;
+
+            if status.success() {
+                if stdout.ends_with('\n') {
+                    stdout.pop();
+
+                    if stdout.ends_with('\r') {
+                        stdout.pop();
+                    }
                 }
-                let status = hook_child.wait().map_err(|e| e.to_string())?;
-                Ok(status.success())
-            },
 
-            None => Ok(false),
+                return Ok(Some(stdout));
+            }
         }
+
+        Ok(None)
     }
 
 }
 
 
+/// Runs the `.postChange` hooks, failing with the hooks that did not succeed.
+impl ChangeListener for Hooks {
+    fn on_change(&self, change: &ChangeEvent, result: &UiResult) -> Result<(), UiError> {
+        let event = HookEvent::from_change(change);
+
+        let failures: Vec<String> = self.run_post_hooks(result, &change.command, Some(&event))?.iter()
+            .filter(|status| !status.success())
+            .map(|status| status.to_string())
+            .collect();
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(UiError::HookFailed(failures))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    use std::os::unix::fs::PermissionsExt;
+
     use ::ui::UiResult;
     use ::cmd::Command;
+    use ::test_util::TempDir;
+
+    fn hook_dir(dir: &Path, hook: &str, script: &str) -> Hooks {
+        let path = dir.join(hook);
+        fs::write(&path, format!("#!/usr/bin/env bash\n\n{}\n", script)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        Hooks::load_from_dir(dir).unwrap()
+    }
 
     #[test]
     fn test_load_from_dir() {
-        let hooks = Hooks::load_from_dir("test/hooks");
+        let hooks = Hooks::load_from_dir("test/hooks").unwrap();
+
+        assert_eq!(vec![Hook::new(PathBuf::from("test/hooks/.preChange"))], hooks.pre_change);
+        assert_eq!(vec![Hook::new(PathBuf::from("test/hooks/.postChange"))], hooks.post_change);
+    }
+
+    #[test]
+    fn test_load_hook_dir() {
+        let hooks = Hooks::load_from_dir("test/hook-dirs").unwrap();
+        let dir = Path::new("test/hook-dirs/.postChange.d");
+
+        assert!(hooks.pre_change.is_empty());
+        assert_eq!(vec![Hook::new(dir.join("10-ok")), Hook::new(dir.join("20-fail")), Hook::new(dir.join("30-ok"))], hooks.post_change);
+    }
+
+    #[test]
+    fn test_scoped_hooks() {
+        let hooks = Hooks::load_from_dir("test/hook-config").unwrap();
+        let dir = Path::new("test/hook-config/scripts");
+        let matching = |line: &str| -> Vec<(&str, PathBuf)> {
+            hooks.matching(&line.parse().unwrap()).into_iter().map(|(stage, hook)| (stage, hook.run.clone())).collect()
+        };
+
+        assert_eq!(vec![("postChange", dir.join("deploy"))], matching("put deploy.web v2"));
+        assert_eq!(vec![("postChange", dir.join("deploy"))], matching("drop deploy.web"));
+        assert!(matching("push deploy.web v2").is_empty());
+        assert_eq!(vec![("preChange", dir.join("guard"))], matching("put secrets/db password"));
+        assert_eq!(vec![("postChange", dir.join("audit"))], matching("purge"));
+        assert!(matching("get deploy.web").is_empty());
+
+        let statuses = hooks.run_post_change_hook(&UiResult::Ok, &Command::Drop("deploy.web".to_string()), None).unwrap();
+        assert_eq!(vec![dir.join("deploy")], statuses.iter().map(|status| status.hook.clone()).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let dir = TempDir::new("hooks-config");
+        fs::write(dir.join(CONFIG_FILE), r#"{"postChange": [{"run": "x", "key": "typo"}]}"#).unwrap();
+
+        match Hooks::load_from_dir(&dir) {
+            Err(HookError::ConfigError(..)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_run_hook_dir() {
+        let mut hooks = Hooks::load_from_dir("test/hook-dirs").unwrap();
+        let command = Command::Drop("test".to_string());
+
+        let codes: Vec<Option<i32>> = hooks.run_post_change_hook(&UiResult::Ok, &command, None).unwrap().iter().map(|s| s.code).collect();
+        assert_eq!(vec![Some(0), Some(3), Some(0)], codes);
+
+        hooks.set_stop_on_failure(true);
+        let statuses = hooks.run_post_change_hook(&UiResult::Ok, &command, None).unwrap();
+        assert_eq!(2, statuses.len());
+        assert!(!statuses[1].success());
+    }
+
+    #[test]
+    fn test_run_pre_change() {
+        let hooks = Hooks::load_from_dir("test/hooks").unwrap();
+
+        assert_eq!(None, hooks.run_pre_change_hook(&Command::PutString("key".to_string(), "value".to_string()), None).unwrap());
 
-        assert_eq!(Some(PathBuf::from("test/hooks/.postChange")), hooks.post_change);
+        let result = hooks.run_pre_change_hook(&Command::PutString("readonly.key".to_string(), "value".to_string()), None).unwrap();
+        assert_eq!(Some("readonly.key is read only".to_string()), result);
     }
 
     #[test]
     fn test_run_post_change() {
-        let hooks = Hooks::load_from_dir("test/hooks");
+        let hooks = Hooks::load_from_dir("test/hooks").unwrap();
 
-        let result = hooks.run_post_change_hook(&UiResult::Ok, &Command::Init);
+        let result = hooks.run_post_change_hook(&UiResult::Ok, &Command::Init(false), None).unwrap();
 
-        assert_eq!(Ok(true), result);
+        assert!(result.iter().all(HookStatus::success));
     }
 
     #[test]
     fn test_run_post_change_complext_command() {
-        let hooks = Hooks::load_from_dir("test/hooks");
+        let hooks = Hooks::load_from_dir("test/hooks").unwrap();
 
-        let result = hooks.run_post_change_hook(&UiResult::StringValueResult("test".to_string()), &Command::Drop("test".to_string()));
+        let result = hooks.run_post_change_hook(&UiResult::StringValueResult("test".to_string()), &Command::Drop("test".to_string()), None).unwrap();
 
-        assert_eq!(Ok(true), result);
+        assert!(result.iter().all(HookStatus::success));
     }
-}
 
+    #[test]
+    fn test_secret_is_masked() {
+        let command = Command::PutSecret("token".to_string(), "abc".to_string());
+
+        assert_eq!(("put", Some("token"), vec![SECRET_MASK.to_string()]), get_hook_str(&command));
+    }
+
+    #[test]
+    fn test_environment() {
+        let dir = TempDir::new("hooks-env");
+        let hooks = hook_dir(&dir, ".preChange", "echo \"$KVS2_ACTION $KVS2_KEY $KVS2_VALUE_TYPE $KVS2_STORE\" >&2\nexit 1");
+        let command = Command::PutString("key".to_string(), "value".to_string());
+        let mut event = HookEvent::new(&command, "store.json");
+        event.value_type = Some("string".to_string());
+
+        let result = hooks.run_pre_change_hook(&command, Some(&event)).unwrap();
+        assert_eq!(Some("put key string store.json".to_string()), result);
+    }
+
+    #[test]
+    fn test_timeout_kills_hook() {
+        let dir = TempDir::new("hooks-timeout");
+        let mut hooks = hook_dir(&dir, ".postChange", "sleep 5");
+        hooks.set_timeout(Some(Duration::from_millis(200)));
+
+        let start = Instant::now();
+        let statuses = hooks.run_post_change_hook(&UiResult::Ok, &Command::Drop("key".to_string()), None).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        assert_eq!(1, statuses.len());
+        assert!(!statuses[0].success());
+        assert!(statuses[0].to_string().contains("was killed after running"));
+    }
+
+    #[test]
+    fn test_timeout_kills_process_group() {
+        let dir = TempDir::new("hooks-group");
+        let mut hooks = hook_dir(&dir, ".postChange", "(sleep 1; touch \"$(dirname \"$0\")/survived\") &\nsleep 5");
+        hooks.set_timeout(Some(Duration::from_millis(200)));
+
+        let statuses = hooks.run_post_change_hook(&UiResult::Ok, &Command::Drop("key".to_string()), None).unwrap();
+        assert!(!statuses[0].success());
+
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!dir.join("survived").exists());
+    }
+
+    #[test]
+    fn test_failing_hooks_do_not_stop_the_others() {
+        let dir = TempDir::new("hooks-keep-going");
+        fs::create_dir_all(dir.join(".postChange.d")).unwrap();
+
+        let script = dir.join(".postChange.d/10-timeout");
+        fs::write(&script, "#!/usr/bin/env bash\n\nsleep 5\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let script = dir.join(".postChange.d/20-broken");
+        fs::write(&script, "#!/nonexistent/interpreter\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let script = dir.join(".postChange.d/30-ok");
+        fs::write(&script, "#!/usr/bin/env bash\n\nexit 0\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut hooks = Hooks::load_from_dir(&dir).unwrap();
+        hooks.set_timeout(Some(Duration::from_millis(200)));
+
+        let statuses = hooks.run_post_change_hook(&UiResult::Ok, &Command::Drop("key".to_string()), None).unwrap();
+        assert_eq!(vec![false, false, true], statuses.iter().map(HookStatus::success).collect::<Vec<_>>());
+        assert!(statuses[1].to_string().starts_with("could not run hook"));
+    }
+
+    #[test]
+    fn test_on_miss() {
+        let dir = TempDir::new("hooks-miss");
+        let hooks = hook_dir(&dir, ".onMiss", "[ \"$2\" = unknown ] && exit 1\necho \"value of $KVS2_KEY\"");
+
+        assert_eq!(Some("value of host".to_string()), hooks.run_on_miss_hooks("host", "store.json").unwrap());
+        assert_eq!(None, hooks.run_on_miss_hooks("unknown", "store.json").unwrap());
+    }
+}
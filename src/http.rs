@@ -0,0 +1,520 @@
+use std;
+use std::io::{
+  self,
+  BufRead,
+  BufReader,
+  BufWriter,
+  Read,
+  Write,
+};
+use std::net::{
+  TcpListener,
+  TcpStream,
+};
+
+use serde_json;
+
+use ::{
+  KVError,
+  Value,
+};
+
+use ::cmd::Command;
+
+use ::server::Server;
+
+use ::backend::Backend;
+
+use ::ui::{
+  UiError,
+  SECRET_MASK,
+};
+
+type Result<T> = std::result::Result<T, UiError>;
+
+/// Largest request body accepted, in bytes. Larger requests are answered with 413.
+pub const MAX_BODY_LEN: usize = 16 * 1024 * 1024;
+
+/// Longest request line accepted, in bytes. Longer ones are answered with 414.
+pub const MAX_REQUEST_LINE_LEN: usize = 8 * 1024;
+
+/// Longest header line accepted, in bytes. Longer ones are answered with 431.
+pub const MAX_HEADER_LEN: usize = 8 * 1024;
+
+/// Most header lines accepted. Requests with more are answered with 431.
+pub const MAX_HEADERS: usize = 100;
+
+/// Why a request could not be read.
+#[derive(Debug)]
+pub enum RequestError {
+  Invalid(io::Error),
+  UriTooLong,
+  HeadersTooLarge,
+  BodyTooLarge(usize),
+}
+
+impl From<io::Error> for RequestError {
+  fn from(e: io::Error) -> Self {
+    RequestError::Invalid(e)
+  }
+}
+
+/// A request as far as the REST API cares about it.
+#[derive(Debug)]
+pub struct Request {
+  pub method: String,
+  pub path: String,
+  pub query: String,
+  pub body: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Response {
+  pub status: u16,
+  pub body: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+  error: String,
+}
+
+fn reason(status: u16) -> &'static str {
+  match status {
+    200 => "OK",
+    204 => "No Content",
+    400 => "Bad Request",
+    404 => "Not Found",
+    405 => "Method Not Allowed",
+    409 => "Conflict",
+    413 => "Payload Too Large",
+    414 => "URI Too Long",
+    431 => "Request Header Fields Too Large",
+    _ => "Internal Server Error",
+  }
+}
+
+fn status_for(e: &UiError) -> u16 {
+  match *e {
+    UiError::NoValueForKey(_) => 404,
+    UiError::AlreadyValuePresent(_) => 409,
+    UiError::CmdError(_) => 400,
+    UiError::Contended(_) => 409,
+    UiError::KvError(KVError::EmptyList(_)) => 404,
+    UiError::KvError(KVError::WrongType(..)) => 409,
+    _ => 500,
+  }
+}
+
+impl Response {
+  fn json<T: ::serde::Serialize>(status: u16, body: &T) -> Response {
+    match serde_json::to_string(body) {
+      Ok(body) => Response { status: status, body: Some(body) },
+      Err(e) => Response::error(500, e),
+    }
+  }
+
+  fn no_content() -> Response {
+    Response { status: 204, body: None }
+  }
+
+  fn error<S: ToString>(status: u16, msg: S) -> Response {
+    let body = ErrorBody { error: msg.to_string() };
+    Response {
+      status: status,
+      body: Some(serde_json::to_string(&body).unwrap_or_default()),
+    }
+  }
+
+  fn from_error(e: &UiError) -> Response {
+    Response::error(status_for(e), e)
+  }
+
+  pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+    let body = self.body.as_ref().map_or("", String::as_str);
+
+    write!(w, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+    if self.body.is_some() {
+      write!(w, "Content-Type: application/json\r\n")?;
+    }
+    write!(w, "Content-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body)
+  }
+}
+
+fn invalid_data<S: ToString>(msg: S) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads a line of at most `limit` bytes, failing with `too_long` rather than reading on.
+fn read_line<R: BufRead>(reader: &mut R, limit: usize, too_long: RequestError) -> std::result::Result<String, RequestError> {
+  let mut line = Vec::new();
+
+  if reader.by_ref().take(limit as u64 + 1).read_until(b'\n', &mut line)? > limit {
+    return Err(too_long);
+  }
+
+  Ok(String::from_utf8(line).map_err(invalid_data)?)
+}
+
+pub fn read_request<R: BufRead>(reader: &mut R) -> std::result::Result<Request, RequestError> {
+  let request_line = read_line(reader, MAX_REQUEST_LINE_LEN, RequestError::UriTooLong)?;
+
+  let mut parts = request_line.split_whitespace();
+  let method = parts.next().ok_or(invalid_data("missing method"))?.to_string();
+  let target = parts.next().ok_or(invalid_data("missing request target"))?;
+
+  let (path, query) = match target.find('?') {
+    Some(i) => (&target[..i], &target[i + 1..]),
+    None => (target, ""),
+  };
+
+  let mut content_length = 0;
+
+  for count in 0.. {
+    let header = read_line(reader, MAX_HEADER_LEN, RequestError::HeadersTooLarge)?;
+    let header = header.trim_end();
+
+    if header.is_empty() {
+      break;
+    }
+
+    if count == MAX_HEADERS {
+      return Err(RequestError::HeadersTooLarge);
+    }
+
+    if let Some(i) = header.find(':') {
+      if header[..i].eq_ignore_ascii_case("Content-Length") {
+        content_length = header[i + 1..].trim().parse().map_err(|_| invalid_data("invalid Content-Length"))?;
+      }
+    }
+  }
+
+  if content_length > MAX_BODY_LEN {
+    return Err(RequestError::BodyTooLarge(content_length));
+  }
+
+  let mut body = Vec::new();
+  reader.by_ref().take(content_length as u64).read_to_end(&mut body)?;
+
+  if body.len() != content_length {
+    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "request body shorter than its Content-Length").into());
+  }
+
+  Ok(Request {
+    method: method,
+    path: path.to_string(),
+    query: query.to_string(),
+    body: String::from_utf8(body).map_err(invalid_data)?,
+  })
+}
+
+/// Decodes `%XX` escapes and, in query strings, `+` as space.
+fn percent_decode(s: &str, plus_as_space: bool) -> Option<String> {
+  let bytes = s.as_bytes();
+  let mut decoded = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+
+  while i < bytes.len() {
+    match bytes[i] {
+      b'%' => {
+        let hex = s.get(i + 1..i + 3)?;
+        decoded.push(u8::from_str_radix(hex, 16).ok()?);
+        i += 3;
+      },
+      b'+' if plus_as_space => {
+        decoded.push(b' ');
+        i += 1;
+      },
+      b => {
+        decoded.push(b);
+        i += 1;
+      },
+    }
+  }
+
+  String::from_utf8(decoded).ok()
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+  query.split('&')
+    .filter_map(|pair| {
+      let mut kv = pair.splitn(2, '=');
+      match (kv.next(), kv.next()) {
+        (Some(k), v) if k == name => percent_decode(v.unwrap_or(""), true),
+        _ => None,
+      }
+    })
+    .next()
+}
+
+/// Routes a request onto the store of `server`.
+///
+/// * `GET /keys?prefix=P` lists the keys starting with `P`
+/// * `GET /keys/{key}` returns the value as JSON
+/// * `PUT /keys/{key}` stores the JSON string in the body
+/// * `DELETE /keys/{key}` drops the key
+/// * `POST /keys/{key}/push` pushes the JSON string in the body onto a list, creating it if missing
+/// * `POST /keys/{key}/pop` pops the last value of a list
+pub fn handle_request<B: Backend>(server: &Server<B>, request: &Request) -> Response {
+  let segments: Option<Vec<String>> = request.path.trim_matches('/').split('/')
+    .map(|segment| percent_decode(segment, false))
+    .collect();
+
+  let segments = match segments {
+    Some(segments) => segments,
+    None => return Response::error(400, "invalid path encoding"),
+  };
+
+  let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+
+  match (request.method.as_str(), &segments[..]) {
+    ("GET", ["keys"]) => list_keys(server, &query_param(&request.query, "prefix").unwrap_or_default()),
+    ("GET", ["keys", key]) => get(server, key),
+    ("PUT", ["keys", key]) => with_body(request, |value| execute(server, Command::PutString(key.to_string(), value))),
+    ("DELETE", ["keys", key]) => execute(server, Command::Drop(key.to_string())),
+    ("POST", ["keys", key, "push"]) => push(server, key, request),
+    ("POST", ["keys", key, "pop"]) => pop(server, key),
+    (_, ["keys"]) | (_, ["keys", _]) | (_, ["keys", _, "push"]) | (_, ["keys", _, "pop"]) => Response::error(405, "method not allowed"),
+    _ => Response::error(404, "not found"),
+  }
+}
+
+fn execute<B: Backend>(server: &Server<B>, command: Command) -> Response {
+  match server.execute_command(&command) {
+    Ok(_) => Response::no_content(),
+    Err(e) => Response::from_error(&e),
+  }
+}
+
+fn with_body<F: FnOnce(String) -> Response>(request: &Request, f: F) -> Response {
+  match serde_json::from_str::<String>(&request.body) {
+    Ok(value) => f(value),
+    Err(e) => Response::error(400, format!("body must be a JSON string: {}", e)),
+  }
+}
+
+fn list_keys<B: Backend>(server: &Server<B>, prefix: &str) -> Response {
+  let mut keys: Vec<String> = server.with_store(|kvs| {
+    kvs.get_keys().into_iter().filter(|key| key.starts_with(prefix)).cloned().collect()
+  });
+  keys.sort();
+
+  Response::json(200, &keys)
+}
+
+fn get<B: Backend>(server: &Server<B>, key: &str) -> Response {
+  let value = server.with_store(|kvs| match kvs.get(&key) {
+    Some(_) if kvs.is_secret(key) => Some(Ok(serde_json::Value::String(SECRET_MASK.to_string()))),
+    value => value.map(Value::to_json),
+  });
+
+  match value {
+    Some(Ok(value)) => Response::json(200, &value),
+    Some(Err(e)) => Response::error(500, e),
+    None => Response::from_error(&UiError::NoValueForKey(key.to_string())),
+  }
+}
+
+/// Pushes onto the list at `key`, creating the list if missing.
+fn push<B: Backend>(server: &Server<B>, key: &str, request: &Request) -> Response {
+  with_body(request, |value| execute(server, Command::PushListValues(key.to_string(), vec![value])))
+}
+
+fn pop<B: Backend>(server: &Server<B>, key: &str) -> Response {
+  match server.execute_command(&Command::PopListValue(key.to_string())) {
+    Ok(result) => Response::json(200, &result.to_string()),
+    Err(e) => Response::from_error(&e),
+  }
+}
+
+pub fn handle_connection<B: Backend>(server: &Server<B>, stream: TcpStream) -> Result<()> {
+  let mut reader = BufReader::new(stream.try_clone()?);
+  let mut writer = BufWriter::new(stream);
+
+  let response = match read_request(&mut reader) {
+    Ok(request) => handle_request(server, &request),
+    Err(RequestError::UriTooLong) => Response::error(414, format!("request line exceeds the limit of {} bytes", MAX_REQUEST_LINE_LEN)),
+    Err(RequestError::HeadersTooLarge) => Response::error(431, format!("request headers exceed the limit of {} lines of {} bytes", MAX_HEADERS, MAX_HEADER_LEN)),
+    Err(RequestError::BodyTooLarge(len)) => Response::error(413, format!("request body of {} bytes exceeds the limit of {} bytes", len, MAX_BODY_LEN)),
+    Err(RequestError::Invalid(e)) => Response::error(400, e),
+  };
+
+  response.write_to(&mut writer)?;
+  writer.flush()?;
+
+  Ok(())
+}
+
+pub fn serve<B: Backend + 'static>(server: Server<B>, listener: TcpListener) -> Result<()> {
+  server.serve_with(listener, handle_connection)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs;
+  use std::os::unix::fs::PermissionsExt;
+  use std::thread;
+  use std::time::Duration;
+
+  use ::hooks::Hooks;
+  use ::server::FlushPolicy;
+  use ::test_util::TempDir;
+  use ::ui::Ui;
+
+  fn start_server(name: &str) -> (TempDir, ::std::net::SocketAddr) {
+    start_server_with(name, &[])
+  }
+
+  /// Starts a server on a store to which `commands` have been applied.
+  fn start_server_with(name: &str, commands: &[Command]) -> (TempDir, ::std::net::SocketAddr) {
+    start_server_in(TempDir::new(&format!("http-{}", name)), commands)
+  }
+
+  /// Starts a server on a new store in `dir`, with the hooks found there.
+  fn start_server_in(dir: TempDir, commands: &[Command]) -> (TempDir, ::std::net::SocketAddr) {
+    let path = dir.join(".kvs.json");
+    ::init(&path).unwrap();
+
+    let ui: Ui = Ui::new("kvs2".to_string(), path.to_str().unwrap().to_string(), false, Hooks::load_from_dir(&dir).unwrap(), Duration::from_secs(1), ::StorageMode::Snapshot);
+    let server = Server::new(ui, FlushPolicy::Always).unwrap();
+
+    for command in commands {
+      server.execute_command(command).unwrap();
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || serve(server, listener));
+
+    (dir, addr)
+  }
+
+  fn call(addr: ::std::net::SocketAddr, method: &str, target: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}", method, target, body.len(), body).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+
+    let status = response[9..12].parse().unwrap();
+    let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+    (status, body)
+  }
+
+  #[test]
+  fn test_strings() {
+    let (_dir, addr) = start_server("strings");
+
+    assert_eq!((204, "".to_string()), call(addr, "PUT", "/keys/greeting%20text", "\"hello world\""));
+    assert_eq!((200, "\"hello world\"".to_string()), call(addr, "GET", "/keys/greeting%20text", ""));
+    assert_eq!(404, call(addr, "GET", "/keys/missing", "").0);
+    assert_eq!(204, call(addr, "DELETE", "/keys/greeting%20text", "").0);
+    assert_eq!(404, call(addr, "DELETE", "/keys/greeting%20text", "").0);
+    assert_eq!(400, call(addr, "PUT", "/keys/bad", "not json").0);
+  }
+
+  #[test]
+  fn test_secret_is_masked() {
+    let (_dir, addr) = start_server_with("secret", &[Command::PutSecret("token".to_string(), "abc".to_string())]);
+
+    assert_eq!((200, format!("\"{}\"", SECRET_MASK)), call(addr, "GET", "/keys/token", ""));
+  }
+
+  #[test]
+  fn test_body_too_large() {
+    let (_dir, addr) = start_server("large");
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "PUT /keys/big HTTP/1.1\r\nContent-Length: 9999999999999\r\n\r\n").unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 413 "));
+
+    let request = read_request(&mut BufReader::new(&b"PUT /keys/k HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"[..]));
+    match request {
+      Err(RequestError::Invalid(ref e)) => assert_eq!(io::ErrorKind::UnexpectedEof, e.kind()),
+      other => panic!("unexpected result {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_request_head_too_large() {
+    let (_dir, addr) = start_server("head");
+
+    // one byte over the limit, all of which the server reads before it replies
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET /keys/{}", "k".repeat(MAX_REQUEST_LINE_LEN - 9)).unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 414 "));
+
+    let read = |head: String| read_request(&mut BufReader::new(head.as_bytes()));
+
+    match read(format!("GET /keys/k HTTP/1.1\r\nX-Long: {}\r\n\r\n", "v".repeat(MAX_HEADER_LEN))) {
+      Err(RequestError::HeadersTooLarge) => (),
+      other => panic!("unexpected result {:?}", other),
+    }
+
+    match read(format!("GET /keys/k HTTP/1.1\r\n{}\r\n", "X-Many: v\r\n".repeat(MAX_HEADERS + 1))) {
+      Err(RequestError::HeadersTooLarge) => (),
+      other => panic!("unexpected result {:?}", other),
+    }
+
+    assert!(read(format!("GET /keys/k HTTP/1.1\r\n{}\r\n", "X-Many: v\r\n".repeat(MAX_HEADERS))).is_ok());
+  }
+
+  #[test]
+  fn test_lists() {
+    let (_dir, addr) = start_server("lists");
+
+    assert_eq!(204, call(addr, "POST", "/keys/queue/push", "\"a\"").0);
+    assert_eq!(204, call(addr, "POST", "/keys/queue/push", "\"b\"").0);
+    assert_eq!((200, "[\"a\",\"b\"]".to_string()), call(addr, "GET", "/keys/queue", ""));
+    assert_eq!((200, "\"b\"".to_string()), call(addr, "POST", "/keys/queue/pop", ""));
+    assert_eq!((200, "\"a\"".to_string()), call(addr, "POST", "/keys/queue/pop", ""));
+    assert_eq!(404, call(addr, "POST", "/keys/queue/pop", "").0);
+    assert_eq!(404, call(addr, "POST", "/keys/missing/pop", "").0);
+  }
+
+  #[test]
+  fn test_failed_hook_keeps_result() {
+    let dir = TempDir::new("http-failed-hook");
+    let hook = dir.join(".postChange");
+    fs::write(&hook, "#!/usr/bin/env bash\n\nexit 1\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+    let (_dir, addr) = start_server_in(dir, &[]);
+
+    assert_eq!(204, call(addr, "POST", "/keys/queue/push", "\"a\"").0);
+    assert_eq!((200, "\"a\"".to_string()), call(addr, "POST", "/keys/queue/pop", ""));
+  }
+
+  #[test]
+  fn test_keys_with_prefix() {
+    let (_dir, addr) = start_server("keys");
+
+    call(addr, "PUT", "/keys/host.web1", "\"10.0.0.1\"");
+    call(addr, "PUT", "/keys/host.web2", "\"10.0.0.2\"");
+    call(addr, "PUT", "/keys/other", "\"value\"");
+
+    assert_eq!((200, "[\"host.web1\",\"host.web2\"]".to_string()), call(addr, "GET", "/keys?prefix=host.", ""));
+    assert_eq!(405, call(addr, "PATCH", "/keys/other", "").0);
+    assert_eq!(404, call(addr, "GET", "/other", "").0);
+  }
+
+  #[test]
+  fn test_conflicting_types() {
+    let (_dir, addr) = start_server("conflict");
+
+    call(addr, "POST", "/keys/queue/push", "\"a\"");
+    assert_eq!(409, call(addr, "PUT", "/keys/queue", "\"x\"").0);
+
+    call(addr, "PUT", "/keys/name", "\"x\"");
+    assert_eq!(409, call(addr, "POST", "/keys/name/push", "\"y\"").0);
+    assert_eq!(409, call(addr, "POST", "/keys/name/pop", "").0);
+    assert_eq!((200, "\"x\"".to_string()), call(addr, "GET", "/keys/name", ""));
+  }
+}
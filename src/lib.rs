@@ -1,6 +1,15 @@
 #[macro_use]
 extern crate serde_derive;
+extern crate serde;
 extern crate serde_json;
+extern crate fs2;
+extern crate chacha20poly1305;
+extern crate getrandom;
+extern crate libc;
+extern crate pbkdf2;
+extern crate rpassword;
+extern crate rustyline;
+extern crate sha2;
 
 pub mod cmd;
 
@@ -8,19 +17,49 @@ pub mod ui;
 
 pub mod hooks;
 
-use std::collections::HashMap;
+pub mod lock;
+
+pub mod server;
+
+pub mod resp;
+
+pub mod http;
+
+pub mod glob;
+
+pub mod oplog;
+
+pub mod backend;
+
+pub mod format;
+
+pub mod verify;
+
+pub mod crypt;
+
+pub mod listener;
+
+pub mod shell;
+
+#[cfg(test)]
+mod test_util;
+
+use std::collections::{
+  BTreeSet,
+  HashMap,
+};
 use std::str::FromStr;
 use std::io::{
   Read,
   Write,
-  BufReader,
-  BufWriter,
 };
 
 use std::path::Path;
-use std::fs::{
-  File,
-  OpenOptions,
+
+use std::time::{
+  Duration,
+  SystemTime,
+  UNIX_EPOCH,
 };
 
 use std::fmt::{
@@ -28,10 +67,31 @@ use std::fmt::{
   Formatter
 };
 
+use cmd::Command;
+
+use format::Format;
+
+use backend::{
+  Backend,
+  JsonFileBackend,
+  MemoryBackend,
+};
+
 #[derive(Debug)]
 pub enum KVError {
   IoError(std::io::Error),
   EncodingError(serde_json::Error),
+  PersistError(String, std::io::Error),
+  CorruptLog(String),
+  CorruptStore(String),
+  UnsupportedVersion(u64, u64),
+  ChecksumMismatch(String),
+  DecryptionFailed,
+  NotAnInteger(String),
+  IntegerOverflow(String),
+  /// The value at the key is not of the named type.
+  WrongType(String, &'static str),
+  EmptyList(String),
   UnknownError(String),
 }
 
@@ -40,6 +100,16 @@ impl Display for KVError {
     match *self {
       KVError::IoError(ref e) => write!(f, "{}", e.to_string()),
       KVError::EncodingError(ref e) => write!(f, "{}", e.to_string()),
+      KVError::PersistError(ref step, ref e) => write!(f, "could not persist store ({}): {}", step, e),
+      KVError::CorruptLog(ref msg) => write!(f, "corrupt operation log: {}", msg),
+      KVError::CorruptStore(ref msg) => write!(f, "corrupt store: {}", msg),
+      KVError::UnsupportedVersion(version, supported) => write!(f, "store format version {} is newer than the supported version {}, please upgrade kvs2", version, supported),
+      KVError::ChecksumMismatch(ref msg) => write!(f, "store checksum mismatch ({}), run verify to locate the damage", msg),
+      KVError::DecryptionFailed => write!(f, "cannot decrypt the store: wrong passphrase or the file has been tampered with"),
+      KVError::NotAnInteger(ref key) => write!(f, "value at {} is not an integer", key),
+      KVError::IntegerOverflow(ref key) => write!(f, "integer overflow at {}", key),
+      KVError::WrongType(ref key, expected) => write!(f, "value at {} not a {}", key, expected),
+      KVError::EmptyList(ref key) => write!(f, "list at {} is empty", key),
       KVError::UnknownError(ref msg) => write!(f, "{}", msg),
     }
   }
@@ -60,73 +130,198 @@ impl From<std::io::Error> for KVError {
 }
 
 pub fn init<P: AsRef<Path>>(path: P) -> Result<KVStore> {
-  let file = File::create(path)?;
-  let mut writer = BufWriter::new(file);
-
-  let kvs = KVStore::new();
-  kvs.write(&mut writer)?;
+  let mut kvs = KVStore::open(path.as_ref());
+  kvs.flush()?;
 
   Ok(kvs)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct KVStore {
-  content: HashMap<String, Value>
+fn unix_now() -> u64 {
+  SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// A key value store, keeping its values in the backend `B`.
+#[derive(Debug)]
+pub struct KVStore<B: Backend = JsonFileBackend> {
+  backend: B,
+  /// The time expiry is judged against while replaying a change, instead of the clock.
+  now: Option<u64>,
 }
 
-impl FromStr for KVStore {
+/// How changes made through the `Ui` are persisted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageMode {
+  /// Rewrite the whole store on every change.
+  Snapshot,
+  /// Append each change to an operation log, and fold the log into the store
+  /// once it grows beyond the given number of bytes.
+  AppendLog(u64),
+}
+
+impl FromStr for KVStore<MemoryBackend> {
   type Err = KVError;
 
   fn from_str(s: &str) -> Result<Self> {
-    Ok(serde_json::from_str(s)?)
+    KVStore::read(s.as_bytes())
   }
 }
 
-impl ToString for KVStore {
+impl ToString for KVStore<MemoryBackend> {
   fn to_string(&self) -> String {
     self.serialize().unwrap()
   }
 }
 
-impl KVStore {
-  pub fn new() -> KVStore {
+impl KVStore<MemoryBackend> {
+  pub fn new() -> KVStore<MemoryBackend> {
+    KVStore::with_backend(MemoryBackend::new())
+  }
+
+  /// Reads a store in any `Format`, migrating it to the current format version if it is older.
+  pub fn read<R: Read>(r: R) -> Result<KVStore<MemoryBackend>> {
+    Ok(KVStore::with_backend(MemoryBackend::read(r)?))
+  }
+
+  pub fn serialize(&self) -> Result<String> {
+    self.backend.serialize()
+  }
+
+  pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+    self.backend.write(w)
+  }
+
+  pub fn write_format<W: Write>(&self, w: &mut W, format: Format) -> Result<()> {
+    self.backend.write_format(w, format)
+  }
+
+  /// Writes the store to `path` without ever leaving a partially written file behind.
+  pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+    self.backend.write_to_file(path.as_ref())
+  }
+}
+
+impl KVStore<JsonFileBackend> {
+  /// Reads the store at `path` and replays its operation log, if there is one.
+  pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<KVStore> {
+    let mut kvs = KVStore::open(path.as_ref());
+    kvs.load()?;
+
+    Ok(kvs)
+  }
+}
+
+impl<B: Backend> KVStore<B> {
+  pub fn with_backend(backend: B) -> KVStore<B> {
     KVStore {
-      content: HashMap::new()
+      backend: backend,
+      now: None,
     }
   }
 
-  pub fn read<R: Read>(r: R) -> Result<KVStore> {
-    Ok(serde_json::from_reader(r)?)
+  /// Opens the store at `location` without loading it yet.
+  pub fn open(location: &Path) -> KVStore<B> {
+    KVStore::with_backend(B::open(location))
   }
 
-  pub fn read_from_file<P: AsRef<Path>>(path: P) -> Result<KVStore> {
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
+  pub fn backend(&self) -> &B {
+    &self.backend
+  }
 
-    KVStore::read(reader)
+  pub fn backend_mut(&mut self) -> &mut B {
+    &mut self.backend
   }
 
-  pub fn serialize(&self) -> Result<String> {
-    Ok(serde_json::to_string(self)?)
+  pub fn exists(&self) -> bool {
+    self.backend.exists()
   }
 
-  pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
-    Ok(serde_json::to_writer_pretty(w, self)?)
+  /// Loads the store from its backend and replays its operation log, if there is one.
+  pub fn load(&mut self) -> Result<()> {
+    self.backend.load()?;
+
+    if let Some(log_path) = self.backend.log_path() {
+      oplog::replay(self, &log_path)?;
+    }
+
+    Ok(())
   }
 
-  pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
-    let file = OpenOptions::new().write(true).truncate(true).open(path)?;
-    let mut writer = BufWriter::new(file);
+  /// Writes all changes to the backend, creating the store if needed.
+  pub fn flush(&mut self) -> Result<()> {
+    self.backend.flush()
+  }
+
+  /// Applies a change `command` as if it had run at `time` (seconds since the unix epoch).
+  ///
+  /// Unlike the `Ui`, this does not validate the command; it is used to replay changes that already succeeded.
+  pub fn apply_at(&mut self, command: &Command, time: u64) -> Result<()> {
+    let now = self.now.replace(time);
+    let result = self.apply(command, time);
+    self.now = now;
+
+    result
+  }
+
+  fn apply(&mut self, command: &Command, time: u64) -> Result<()> {
+    match *command {
+      Command::PutString(ref key, ref value) => self.put(key, value),
+      Command::PutSecret(ref key, ref value) => self.put_secret(key, value),
+      Command::PutStringWithTtl(ref key, ref value, ttl) => {
+        self.put(key, value);
+        self.expire_at(key, time.saturating_add(ttl));
+      },
+      Command::Drop(ref key) => { self.drop(key); },
+      Command::Expire(ref key, ttl) => { self.expire_at(key, time.saturating_add(ttl)); },
+      Command::Persist(ref key) => { self.persist(key); },
+      Command::Purge => { self.purge_expired(); },
+      Command::CreateEmptyList(ref key) | Command::ClearList(ref key) => { self.put_empty_list(key); },
+      Command::PushListValue(ref key, ref value) => self.push_value(key, value)?,
+      Command::PushListValues(ref key, ref values) => { self.push_values(key, values)?; },
+      Command::PushFrontListValues(ref key, ref values) => { self.push_front_values(key, values)?; },
+      Command::PopListValue(ref key) => { self.pop_value(key)?; },
+      Command::Increment(ref key, by) => { self.increment(key, by)?; },
+      Command::Decrement(ref key, by) => { self.decrement(key, by)?; },
+      Command::MapSet(ref key, ref field, ref value) => { self.put_map_value(key, field, value)?; },
+      Command::MapDelete(ref key, ref field) => { self.remove_map_value(key, field)?; },
+      Command::SetAdd(ref key, ref member) => { self.add_set_member(key, member)?; },
+      Command::SetRemove(ref key, ref member) => { self.remove_set_member(key, member)?; },
+      _ => (),
+    }
+
+    Ok(())
+  }
+
+  fn now(&self) -> u64 {
+    self.now.unwrap_or_else(unix_now)
+  }
+
+  fn is_expired(&self, key: &str) -> bool {
+    self.backend.expires().get(key).is_some_and(|&expires_at| expires_at <= self.now())
+  }
 
-    self.write(&mut writer)
+  /// Removes `key` if its time to live has run out, so it can be treated as missing.
+  fn remove_if_expired(&mut self, key: &str) {
+    if self.is_expired(key) {
+      self.backend.remove(key);
+      self.backend.expires_mut().remove(key);
+      self.backend.secrets_mut().remove(key);
+    }
   }
 
   fn put_value<S: ToString>(&mut self, key: S, value: Value) -> Option<Value> {
-    self.content.insert(key.to_string(), value)
+    let key = key.to_string();
+    self.remove_if_expired(&key);
+    self.backend.expires_mut().remove(&key);
+    self.backend.secrets_mut().remove(&key);
+
+    self.backend.put(key, value)
   }
 
   fn get_mut<S: ToString>(&mut self, key: S) -> Result<&mut Value> {
-    self.content.get_mut(&key.to_string()).ok_or(KVError::UnknownError("no key".to_string()))
+    let key = key.to_string();
+    self.remove_if_expired(&key);
+
+    self.backend.get_mut(&key).ok_or(KVError::UnknownError("no key".to_string()))
   }
 
   fn get_mut_list<S: ToString>(&mut self, key: S) -> Result<&mut Vec<String>> {
@@ -136,12 +331,56 @@ impl KVStore {
     if let &mut Value::ListValue(ref mut list) = kv_value {
       Ok(list)
     } else {
-      Err(KVError::UnknownError(format!("value at {} not a list", &key)))
+      Err(KVError::WrongType(key, "list"))
+    }
+  }
+
+  fn get_mut_map<S: ToString>(&mut self, key: S) -> Result<&mut HashMap<String, String>> {
+    let key = key.to_string();
+    let kv_value = self.get_mut(&key)?;
+
+    if let &mut Value::MapValue(ref mut map) = kv_value {
+      Ok(map)
+    } else {
+      Err(KVError::WrongType(key, "map"))
+    }
+  }
+
+  pub fn get_map<S: ToString>(&self, key: &S) -> Result<Option<&HashMap<String, String>>> {
+    match self.get(key) {
+      Some(Value::MapValue(map)) => Ok(Some(map)),
+      Some(_) => Err(KVError::WrongType(key.to_string(), "map")),
+      None => Ok(None),
+    }
+  }
+
+  fn get_mut_set<S: ToString>(&mut self, key: S) -> Result<&mut BTreeSet<String>> {
+    let key = key.to_string();
+    let kv_value = self.get_mut(&key)?;
+
+    if let &mut Value::SetValue(ref mut set) = kv_value {
+      Ok(set)
+    } else {
+      Err(KVError::WrongType(key, "set"))
+    }
+  }
+
+  pub fn get_set<S: ToString>(&self, key: &S) -> Result<Option<&BTreeSet<String>>> {
+    match self.get(key) {
+      Some(Value::SetValue(set)) => Ok(Some(set)),
+      Some(_) => Err(KVError::WrongType(key.to_string(), "set")),
+      None => Ok(None),
     }
   }
 
   pub fn get<S: ToString>(&self, key: &S) -> Option<&Value> {
-    self.content.get(&key.to_string())
+    let key = key.to_string();
+
+    if self.is_expired(&key) {
+      None
+    } else {
+      self.backend.get(&key)
+    }
   }
 
   pub fn put<KS: ToString, VS: ToString>(&mut self, key: KS, value: VS) {
@@ -149,6 +388,18 @@ impl KVStore {
     self.put_value(key, kv_value);
   }
 
+  /// Puts a string that is masked in output. Like a time to live, this lasts until the next `put`.
+  pub fn put_secret<KS: ToString, VS: ToString>(&mut self, key: KS, value: VS) {
+    let key = key.to_string();
+    self.put(&key, value);
+    self.backend.secrets_mut().insert(key);
+  }
+
+  pub fn is_secret<KS: ToString>(&self, key: KS) -> bool {
+    let key = key.to_string();
+    self.has_key(&key) && self.backend.secrets().contains(&key)
+  }
+
   pub fn put_empty_list<S: ToString>(&mut self, key: S) -> Option<Value> {
     let empty_list = Vec::new();
     let value = Value::ListValue(empty_list);
@@ -168,21 +419,181 @@ impl KVStore {
   }
 
   pub fn pop_value<KS: ToString>(&mut self, key: KS) -> Result<String> {
-    let list_value = self.get_mut_list(key)?;
+    let key = key.to_string();
+    let list_value = self.get_mut_list(&key)?;
+
+    list_value.pop().ok_or(KVError::EmptyList(key))
+  }
+
+  fn get_or_create_list(&mut self, key: String) -> Result<&mut Vec<String>> {
+    if !self.has_key(&key) {
+      self.put_empty_list(&key);
+    }
+
+    self.get_mut_list(key)
+  }
+
+  /// Pushes `values` to the back of the list at `key`, creating the list if missing. Returns the
+  /// length of the list.
+  pub fn push_values<KS: ToString, VS: ToString>(&mut self, key: KS, values: &[VS]) -> Result<usize> {
+    let list = self.get_or_create_list(key.to_string())?;
+    list.extend(values.iter().map(ToString::to_string));
+
+    Ok(list.len())
+  }
+
+  /// Pushes `values` to the front of the list at `key` one after another, creating the list if
+  /// missing. Returns the length of the list.
+  pub fn push_front_values<KS: ToString, VS: ToString>(&mut self, key: KS, values: &[VS]) -> Result<usize> {
+    let list = self.get_or_create_list(key.to_string())?;
+    for value in values {
+      list.insert(0, value.to_string());
+    }
+
+    Ok(list.len())
+  }
+
+  /// Sets `field` in the map at `key`, creating the map if missing. Returns the previous value of the field.
+  pub fn put_map_value<KS: ToString, FS: ToString, VS: ToString>(&mut self, key: KS, field: FS, value: VS) -> Result<Option<String>> {
+    if !self.has_key(key.to_string()) {
+      self.put_value(key.to_string(), Value::MapValue(HashMap::new()));
+    }
+
+    let map = self.get_mut_map(key)?;
+    Ok(map.insert(field.to_string(), value.to_string()))
+  }
+
+  pub fn remove_map_value<KS: ToString, FS: ToString>(&mut self, key: KS, field: FS) -> Result<Option<String>> {
+    let map = self.get_mut_map(key)?;
+    Ok(map.remove(&field.to_string()))
+  }
+
+  /// Adds `member` to the set at `key`, creating the set if missing. Returns whether it was newly added.
+  pub fn add_set_member<KS: ToString, MS: ToString>(&mut self, key: KS, member: MS) -> Result<bool> {
+    if !self.has_key(key.to_string()) {
+      self.put_value(key.to_string(), Value::SetValue(BTreeSet::new()));
+    }
 
-    list_value.pop().ok_or(KVError::UnknownError("list is empty".to_string()))
+    let set = self.get_mut_set(key)?;
+    Ok(set.insert(member.to_string()))
+  }
+
+  pub fn remove_set_member<KS: ToString, MS: ToString>(&mut self, key: KS, member: MS) -> Result<bool> {
+    let set = self.get_mut_set(key)?;
+    Ok(set.remove(&member.to_string()))
+  }
+
+  /// Returns the sets at `keys`, treating missing keys as empty sets.
+  fn get_sets<S: ToString>(&self, keys: &[S]) -> Result<Vec<BTreeSet<String>>> {
+    keys.iter()
+      .map(|key| self.get_set(key).map(|set| set.cloned().unwrap_or_default()))
+      .collect()
+  }
+
+  pub fn set_union<S: ToString>(&self, keys: &[S]) -> Result<BTreeSet<String>> {
+    let sets = self.get_sets(keys)?;
+    Ok(sets.into_iter().fold(BTreeSet::new(), |acc, set| &acc | &set))
+  }
+
+  pub fn set_intersection<S: ToString>(&self, keys: &[S]) -> Result<BTreeSet<String>> {
+    let mut sets = self.get_sets(keys)?.into_iter();
+    let first = sets.next().unwrap_or_default();
+    Ok(sets.fold(first, |acc, set| &acc & &set))
+  }
+
+  pub fn set_difference<S: ToString>(&self, keys: &[S]) -> Result<BTreeSet<String>> {
+    let mut sets = self.get_sets(keys)?.into_iter();
+    let first = sets.next().unwrap_or_default();
+    Ok(sets.fold(first, |acc, set| &acc - &set))
+  }
+
+  pub fn increment<KS: ToString>(&mut self, key: KS, by: i64) -> Result<i64> {
+    self.update_integer(key, |i| i.checked_add(by))
+  }
+
+  pub fn decrement<KS: ToString>(&mut self, key: KS, by: i64) -> Result<i64> {
+    self.update_integer(key, |i| i.checked_sub(by))
+  }
+
+  /// Applies `f` to the integer at `key`, which is created with a value of 0 if missing.
+  fn update_integer<KS: ToString, F: FnOnce(i64) -> Option<i64>>(&mut self, key: KS, f: F) -> Result<i64> {
+    let key = key.to_string();
+    self.remove_if_expired(&key);
+
+    if self.backend.get(&key).is_none() {
+      self.backend.put(key.clone(), Value::IntValue(0));
+    }
+
+    if let Some(&mut Value::IntValue(ref mut i)) = self.backend.get_mut(&key) {
+      *i = f(*i).ok_or(KVError::IntegerOverflow(key))?;
+      Ok(*i)
+    } else {
+      Err(KVError::NotAnInteger(key))
+    }
   }
 
   pub fn get_keys(&self) -> Vec<&String> {
-    self.content.keys().collect()
+    self.backend.keys().into_iter().filter(|key| !self.is_expired(key)).collect()
   }
 
   pub fn drop<KS: ToString>(&mut self, key: KS) -> Option<Value> {
-    self.content.remove(&key.to_string())
+    let key = key.to_string();
+    self.remove_if_expired(&key);
+    self.backend.expires_mut().remove(&key);
+    self.backend.secrets_mut().remove(&key);
+
+    self.backend.remove(&key)
   }
 
   pub fn has_key<KS: ToString>(&self, key: KS) -> bool {
-    self.content.contains_key(&key.to_string())
+    self.get(&key.to_string()).is_some()
+  }
+
+  /// Lets `key` expire after `ttl`. Returns false if there is no value at `key`.
+  pub fn expire<KS: ToString>(&mut self, key: KS, ttl: Duration) -> bool {
+    self.expire_at(key, self.now().saturating_add(ttl.as_secs()))
+  }
+
+  /// Lets `key` expire at `timestamp` (seconds since the unix epoch). Returns false if there is no value at `key`.
+  pub fn expire_at<KS: ToString>(&mut self, key: KS, timestamp: u64) -> bool {
+    let key = key.to_string();
+
+    if !self.has_key(&key) {
+      return false;
+    }
+
+    self.backend.expires_mut().insert(key, timestamp);
+    true
+  }
+
+  /// Removes the time to live from `key`. Returns false if it had none.
+  pub fn persist<KS: ToString>(&mut self, key: KS) -> bool {
+    let key = key.to_string();
+
+    self.has_key(&key) && self.backend.expires_mut().remove(&key).is_some()
+  }
+
+  /// Returns the remaining time to live of `key`, or None if the key is missing or has no expiry.
+  pub fn get_ttl<KS: ToString>(&self, key: &KS) -> Option<Duration> {
+    let key = key.to_string();
+
+    if !self.has_key(&key) {
+      return None;
+    }
+
+    self.backend.expires().get(&key).map(|&expires_at| Duration::from_secs(expires_at.saturating_sub(self.now())))
+  }
+
+  /// Removes all expired keys from the store and returns them.
+  pub fn purge_expired(&mut self) -> Vec<String> {
+    let mut expired: Vec<String> = self.backend.expires().keys().filter(|key| self.is_expired(key)).cloned().collect();
+    expired.sort();
+
+    for key in &expired {
+      self.remove_if_expired(key);
+    }
+
+    expired
   }
 
   pub fn get_value_type<KS: ToString>(&self, key: &KS) -> Option<ValueType> {
@@ -193,12 +604,30 @@ impl KVStore {
 pub enum ValueType {
   String,
   List,
+  Integer,
+  Map,
+  Set,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum Value {
   StringValue(String),
-  ListValue(Vec<String>)
+  ListValue(Vec<String>),
+  IntValue(i64),
+  MapValue(HashMap<String, String>),
+  SetValue(BTreeSet<String>),
+}
+
+impl ValueType {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      ValueType::String => "string",
+      ValueType::List => "list",
+      ValueType::Integer => "integer",
+      ValueType::Map => "map",
+      ValueType::Set => "set",
+    }
+  }
 }
 
 impl Value {
@@ -206,6 +635,20 @@ impl Value {
     match *self {
       Value::StringValue(_) => ValueType::String,
       Value::ListValue(_) => ValueType::List,
+      Value::IntValue(_) => ValueType::Integer,
+      Value::MapValue(_) => ValueType::Map,
+      Value::SetValue(_) => ValueType::Set,
+    }
+  }
+
+  /// The value as plain JSON, without the type tag it is stored with.
+  pub fn to_json(&self) -> serde_json::Result<serde_json::Value> {
+    match *self {
+      Value::StringValue(ref s) => serde_json::to_value(s),
+      Value::ListValue(ref list) => serde_json::to_value(list),
+      Value::IntValue(i) => serde_json::to_value(i),
+      Value::MapValue(ref map) => serde_json::to_value(map),
+      Value::SetValue(ref set) => serde_json::to_value(set),
     }
   }
 }
@@ -214,8 +657,190 @@ impl Value {
 mod tests {
   use super::*;
 
+  use std::fs;
+
+  use ::test_util::TempDir;
+
   #[test]
   fn test_create() {
     KVStore::new();
   }
+
+  #[test]
+  fn test_increment_creates_key() {
+    let mut kvs = KVStore::new();
+
+    assert_eq!(1, kvs.increment("counter", 1).unwrap());
+    assert_eq!(6, kvs.increment("counter", 5).unwrap());
+    assert_eq!(4, kvs.decrement("counter", 2).unwrap());
+    assert_eq!(-3, kvs.decrement("other", 3).unwrap());
+  }
+
+  #[test]
+  fn test_increment_rejects_non_integer() {
+    let mut kvs = KVStore::new();
+    kvs.put("key", "value");
+
+    match kvs.increment("key", 1) {
+      Err(KVError::NotAnInteger(key)) => assert_eq!("key", key),
+      other => panic!("unexpected result {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_increment_overflow() {
+    let mut kvs = KVStore::new();
+    kvs.increment("counter", i64::MAX).unwrap();
+
+    match kvs.increment("counter", 1) {
+      Err(KVError::IntegerOverflow(key)) => assert_eq!("counter", key),
+      other => panic!("unexpected result {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_map_values() {
+    let mut kvs = KVStore::new();
+
+    assert_eq!(None, kvs.put_map_value("host", "ip", "10.0.0.1").unwrap());
+    assert_eq!(Some("10.0.0.1".to_string()), kvs.put_map_value("host", "ip", "10.0.0.2").unwrap());
+    kvs.put_map_value("host", "port", "22").unwrap();
+
+    assert_eq!(Some(&"10.0.0.2".to_string()), kvs.get_map(&"host").unwrap().unwrap().get("ip"));
+    assert_eq!(Some("22".to_string()), kvs.remove_map_value("host", "port").unwrap());
+    assert_eq!(1, kvs.get_map(&"host").unwrap().unwrap().len());
+    assert!(kvs.get_map(&"missing").unwrap().is_none());
+  }
+
+  #[test]
+  fn test_map_value_on_string_fails() {
+    let mut kvs = KVStore::new();
+    kvs.put("key", "value");
+
+    assert!(kvs.put_map_value("key", "field", "value").is_err());
+    assert!(kvs.get_map(&"key").is_err());
+  }
+
+  #[test]
+  fn test_set_members() {
+    let mut kvs = KVStore::new();
+
+    assert!(kvs.add_set_member("hosts", "web1").unwrap());
+    assert!(!kvs.add_set_member("hosts", "web1").unwrap());
+    assert!(kvs.add_set_member("hosts", "web2").unwrap());
+    assert!(kvs.remove_set_member("hosts", "web2").unwrap());
+    assert!(!kvs.remove_set_member("hosts", "web2").unwrap());
+
+    assert_eq!(1, kvs.get_set(&"hosts").unwrap().unwrap().len());
+  }
+
+  #[test]
+  fn test_set_algebra() {
+    let mut kvs = KVStore::new();
+
+    for member in &["a", "b", "c"] {
+      kvs.add_set_member("first", member).unwrap();
+    }
+    for member in &["b", "c", "d"] {
+      kvs.add_set_member("second", member).unwrap();
+    }
+
+    let keys = ["first", "second"];
+    let as_vec = |set: BTreeSet<String>| set.into_iter().collect::<Vec<_>>();
+
+    assert_eq!(vec!["a", "b", "c", "d"], as_vec(kvs.set_union(&keys).unwrap()));
+    assert_eq!(vec!["b", "c"], as_vec(kvs.set_intersection(&keys).unwrap()));
+    assert_eq!(vec!["a"], as_vec(kvs.set_difference(&keys).unwrap()));
+    assert!(kvs.set_intersection(&["first", "missing"]).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_expired_keys_are_ignored() {
+    let mut kvs = KVStore::new();
+    kvs.put("token", "secret");
+    kvs.put("other", "value");
+
+    assert!(kvs.expire_at("token", 1));
+
+    assert!(kvs.get(&"token").is_none());
+    assert!(!kvs.has_key("token"));
+    assert_eq!(vec!["other"], kvs.get_keys());
+    assert!(!kvs.expire("token", Duration::from_secs(10)));
+  }
+
+  #[test]
+  fn test_ttl_and_persist() {
+    let mut kvs = KVStore::new();
+    kvs.put("token", "secret");
+
+    assert!(kvs.get_ttl(&"token").is_none());
+    assert!(kvs.expire("token", Duration::from_secs(3600)));
+    assert!(kvs.get_ttl(&"token").unwrap() <= Duration::from_secs(3600));
+
+    kvs.put("token", "new secret");
+    assert!(kvs.get_ttl(&"token").is_none());
+
+    kvs.expire("token", Duration::from_secs(3600));
+    assert!(kvs.persist("token"));
+    assert!(!kvs.persist("token"));
+    assert!(kvs.get_ttl(&"token").is_none());
+  }
+
+  #[test]
+  fn test_purge_expired() {
+    let mut kvs = KVStore::new();
+    kvs.put("a", "1");
+    kvs.put("b", "2");
+    kvs.put("c", "3");
+    kvs.expire_at("b", 1);
+    kvs.expire_at("a", 1);
+    kvs.expire("c", Duration::from_secs(3600));
+
+    assert_eq!(vec!["a".to_string(), "b".to_string()], kvs.purge_expired());
+    assert_eq!(1, kvs.backend().keys().len());
+    assert_eq!(1, kvs.backend().expires().len());
+  }
+
+  #[test]
+  fn test_flush_replaces_store() {
+    let dir = TempDir::new("test-write");
+    let path = dir.join(".kvs.json");
+
+    let mut kvs = init(&path).unwrap();
+    kvs.put("key", "value");
+    kvs.flush().unwrap();
+
+    let read = KVStore::read_from_file(&path).unwrap();
+    match read.get(&"key") {
+      Some(Value::StringValue(v)) => assert_eq!("value", v),
+      _ => panic!("value not persisted"),
+    }
+
+    let entries = fs::read_dir(path.parent().unwrap()).unwrap().count();
+    assert_eq!(1, entries);
+  }
+
+  #[test]
+  fn test_write_to_file() {
+    let dir = TempDir::new("test-write-file");
+    let path = dir.join(".kvs.json");
+
+    let mut kvs = KVStore::new();
+    kvs.put("key", "value");
+    kvs.write_to_file(&path).unwrap();
+
+    assert!(KVStore::read_from_file(&path).unwrap().has_key("key"));
+    assert_eq!(1, fs::read_dir(&*dir).unwrap().count());
+  }
+
+  #[test]
+  fn test_flush_failure_is_persist_error() {
+    let dir = TempDir::new("test-fail");
+    let path = dir.join("missing").join(".kvs.json");
+
+    match KVStore::<JsonFileBackend>::open(&path).flush() {
+      Err(KVError::PersistError(..)) => (),
+      other => panic!("unexpected result {:?}", other),
+    }
+  }
 }
@@ -0,0 +1,45 @@
+use ::cmd::Command;
+use ::ui::{
+  UiError,
+  UiResult,
+};
+use ::Value;
+
+/// A change made to a store, as seen by a `ChangeListener`.
+///
+/// `old_value` is the value at `key` before the command and `new_value` the one after it, which
+/// is not known yet before the command has run. Commands without a key, like `purge`, have neither.
+#[derive(Debug, Clone)]
+pub struct ChangeEvent {
+  pub command: Command,
+  pub key: Option<String>,
+  pub old_value: Option<Value>,
+  pub new_value: Option<Value>,
+  /// Whether `key` held or now holds a secret, so that its values should not be shown.
+  pub secret: bool,
+  pub store: String,
+  pub timestamp: u64,
+}
+
+impl ChangeEvent {
+  /// Starts the event for `command` on the store at `store`, without any values yet.
+  pub fn new(command: &Command, store: &str) -> ChangeEvent {
+    ChangeEvent {
+      command: command.clone(),
+      key: command.key().map(str::to_string),
+      old_value: None,
+      new_value: None,
+      secret: false,
+      store: store.to_string(),
+      timestamp: ::unix_now(),
+    }
+  }
+}
+
+/// Gets notified synchronously after each change a `Ui` makes, once the store has been saved.
+///
+/// Listeners are registered with `Ui::add_listener`; the `.postChange` hooks are one of them.
+/// An error does not undo the change, but is reported as the result of the command.
+pub trait ChangeListener: Send + Sync {
+  fn on_change(&self, event: &ChangeEvent, result: &UiResult) -> Result<(), UiError>;
+}
@@ -0,0 +1,142 @@
+use std;
+use std::fmt::{
+  Display,
+  Formatter,
+};
+use std::fs::{
+  File,
+  OpenOptions,
+};
+use std::path::{
+  Path,
+  PathBuf,
+};
+use std::thread;
+use std::time::{
+  Duration,
+  Instant,
+};
+
+use fs2::{
+  self,
+  FileExt,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockMode {
+  Shared,
+  Exclusive,
+}
+
+#[derive(Debug)]
+pub enum LockError {
+  Timeout(PathBuf),
+  IoError(PathBuf, std::io::Error),
+}
+
+impl Display for LockError {
+  fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+    match *self {
+      LockError::Timeout(ref path) => write!(f, "timed out waiting for lock on {}", path.display()),
+      LockError::IoError(ref path, ref e) => write!(f, "could not lock {}: {}", path.display(), e),
+    }
+  }
+}
+
+const RETRY_INTERVAL_MS: u64 = 10;
+
+/// An advisory lock guarding a store against concurrent read-modify-write cycles.
+///
+/// The lock is taken on a separate `<store>.lock` file, since the store itself is replaced
+/// on every write. It is released when the `StoreLock` is dropped.
+#[derive(Debug)]
+pub struct StoreLock {
+  file: File,
+}
+
+pub fn lock_path_for(store_path: &Path) -> PathBuf {
+  let mut lock_path = store_path.as_os_str().to_owned();
+  lock_path.push(".lock");
+  PathBuf::from(lock_path)
+}
+
+impl StoreLock {
+  pub fn acquire(store_path: &Path, mode: LockMode, timeout: Duration) -> Result<StoreLock, LockError> {
+    let lock_path = lock_path_for(store_path);
+
+    let file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&lock_path)
+      .map_err(|e| LockError::IoError(lock_path.clone(), e))?;
+
+    let start = Instant::now();
+
+    loop {
+      let result = match mode {
+        LockMode::Shared => FileExt::try_lock_shared(&file),
+        LockMode::Exclusive => FileExt::try_lock_exclusive(&file),
+      };
+
+      match result {
+        Ok(()) => return Ok(StoreLock { file }),
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => {
+          if start.elapsed() >= timeout {
+            return Err(LockError::Timeout(lock_path));
+          }
+          thread::sleep(Duration::from_millis(RETRY_INTERVAL_MS));
+        },
+        Err(e) => return Err(LockError::IoError(lock_path, e)),
+      }
+    }
+  }
+}
+
+impl Drop for StoreLock {
+  fn drop(&mut self) {
+    let _ = FileExt::unlock(&self.file);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use ::test_util::TempDir;
+
+  #[test]
+  fn test_shared_locks_coexist() {
+    let dir = TempDir::new("lock-shared");
+    let path = dir.join(".kvs.json");
+
+    let _first = StoreLock::acquire(&path, LockMode::Shared, Duration::from_millis(0)).unwrap();
+    let _second = StoreLock::acquire(&path, LockMode::Shared, Duration::from_millis(0)).unwrap();
+  }
+
+  #[test]
+  fn test_exclusive_lock_times_out() {
+    let dir = TempDir::new("lock-exclusive");
+    let path = dir.join(".kvs.json");
+
+    let _held = StoreLock::acquire(&path, LockMode::Exclusive, Duration::from_millis(0)).unwrap();
+
+    match StoreLock::acquire(&path, LockMode::Shared, Duration::from_millis(50)) {
+      Err(LockError::Timeout(p)) => assert_eq!(lock_path_for(&path), p),
+      other => panic!("unexpected result {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_lock_released_on_drop() {
+    let dir = TempDir::new("lock-drop");
+    let path = dir.join(".kvs.json");
+
+    {
+      let _held = StoreLock::acquire(&path, LockMode::Exclusive, Duration::from_millis(0)).unwrap();
+    }
+
+    StoreLock::acquire(&path, LockMode::Exclusive, Duration::from_millis(0)).unwrap();
+  }
+}
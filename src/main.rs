@@ -6,8 +6,25 @@ use kvs2::ui::{
   UiResult,
 };
 
+use kvs2::StorageMode;
+
+use kvs2::backend::{
+  Backend,
+  DirBackend,
+  JsonFileBackend,
+};
+
 use kvs2::hooks;
 
+use kvs2::http;
+
+use kvs2::shell;
+
+use kvs2::server::{
+  FlushPolicy,
+  Server,
+};
+
 use std::env;
 
 use std::fmt::Display;
@@ -16,40 +33,269 @@ use std::process::exit;
 
 use std::path;
 
-use getopts::Options;
+use std::net::TcpListener;
+
+use std::time::Duration;
+
+use getopts::{
+  Matches,
+  Options,
+};
 
 fn die<D: Display>(error: &D) {
     println!("Error: {}", error);
     exit(1);
 }
 
+const FLUSH_HELP: &str = "when to write the store: always, never or every SECONDS (default always)";
+
+fn flush_policy(args: &Matches) -> Result<FlushPolicy, String> {
+  args.opt_str("flush").map_or(Ok(FlushPolicy::Always), |s| s.parse::<FlushPolicy>())
+}
+
+/// Runs `ui`'s store as a server, speaking HTTP if `is_http` is set and the line protocol otherwise.
+fn serve<B: Backend + 'static>(ui: Ui<B>, args: &[String], is_http: bool) {
+  let mut opts = Options::new();
+
+  opts.optopt("l", "listen", "address to listen on (default 127.0.0.1:7878)", "ADDR");
+  opts.optopt("f", "flush", FLUSH_HELP, "POLICY");
+
+  let args = match opts.parse(args) {
+    Ok(m) => m,
+    Err(f) => return die(&f),
+  };
+
+  let flush_policy = match flush_policy(&args) {
+    Ok(policy) => policy,
+    Err(e) => return die(&e),
+  };
+
+  let listen = args.opt_str("listen").unwrap_or("127.0.0.1:7878".to_string());
+
+  let server = match Server::new(ui, flush_policy) {
+    Ok(server) => server,
+    Err(e) => return die(&e),
+  };
+
+  let listener = match TcpListener::bind(&listen) {
+    Ok(listener) => listener,
+    Err(e) => return die(&e),
+  };
+
+  let result = if is_http {
+    http::serve(server, listener)
+  } else {
+    server.serve(listener)
+  };
+
+  if let Err(e) = result {
+    die(&e);
+  }
+}
+
+/// Opens `ui`'s store once and runs the commands typed at a prompt against it.
+fn shell<B: Backend + 'static>(ui: Ui<B>, args: &[String]) {
+  let mut opts = Options::new();
+
+  opts.optopt("f", "flush", FLUSH_HELP, "POLICY");
+
+  let args = match opts.parse(args) {
+    Ok(m) => m,
+    Err(f) => return die(&f),
+  };
+
+  let flush_policy = match flush_policy(&args) {
+    Ok(policy) => policy,
+    Err(e) => return die(&e),
+  };
+
+  let server = match Server::new(ui, flush_policy) {
+    Ok(server) => server,
+    Err(e) => return die(&e),
+  };
+
+  if let Err(e) = shell::run(server) {
+    die(&e);
+  }
+}
+
+/// The options taking a value that can be given anywhere on the command line, as short name, long
+/// name, description and value hint.
+const GLOBAL_OPTS: &[(&str, &str, &str, &str)] = &[
+  ("s", "store", "kv store to use (default .kvs.json, or .kvs with the dir backend)", "STORE"),
+  ("", "backend", "how the store is kept: json for a single file or dir for a file per key (default json)", "BACKEND"),
+  ("", "lock-timeout", "seconds to wait for a lock on the store (default 10)", "SECONDS"),
+  ("", "compact-threshold", "log size in bytes at which it is folded into the store (default 1048576)", "BYTES"),
+  ("", "hook-timeout", "seconds after which a hook is killed, 0 for no limit (default 60)", "SECONDS"),
+];
+
+/// The flags that can be given anywhere on the command line, as short name, long name and description.
+const GLOBAL_FLAGS: &[(&str, &str, &str)] = &[
+  ("n", "number", "enumerate list values"),
+  ("", "append-log", "append changes to an operation log instead of rewriting the store"),
+  ("", "stop-on-hook-failure", "skip the remaining postChange hooks once one fails"),
+  ("", "json-hook-events", "pass hooks a JSON description of the change on stdin"),
+];
+
+/// Whether `arg` names the option `short` or `long`, returning whether its value is attached too,
+/// as in `-sSTORE` or `--store=STORE`.
+fn names_option(arg: &str, short: &str, long: &str) -> Option<bool> {
+  if !short.is_empty() && arg.starts_with('-') && !arg.starts_with("--") && arg[1..].starts_with(short) {
+    return Some(arg.len() > 1 + short.len());
+  }
+
+  if arg.starts_with("--") && arg[2..].starts_with(long) {
+    match arg[2 + long.len()..].chars().next() {
+      None => return Some(false),
+      Some('=') => return Some(true),
+      Some(_) => (),
+    }
+  }
+
+  None
+}
+
+/// Splits `args` into the global options, which may come before or after the command, and the
+/// command with its own options, like `put --ttl` or `serve --listen`, which getopts would reject
+/// as unknown. Everything after `--` belongs to the command.
+fn split_global_options(args: &[String]) -> (Vec<String>, Vec<String>) {
+  let mut global = Vec::new();
+  let mut command = Vec::new();
+  let mut args = args.iter();
+
+  while let Some(arg) = args.next() {
+    if arg == "--" {
+      command.extend(args.cloned());
+      break;
+    }
+
+    if GLOBAL_FLAGS.iter().any(|&(short, long, _)| names_option(arg, short, long) == Some(false)) {
+      global.push(arg.clone());
+      continue;
+    }
+
+    match GLOBAL_OPTS.iter().filter_map(|&(short, long, _, _)| names_option(arg, short, long)).next() {
+      Some(attached) => {
+        global.push(arg.clone());
+
+        if !attached {
+          global.extend(args.next().cloned());
+        }
+      },
+      None => command.push(arg.clone()),
+    }
+  }
+
+  (global, command)
+}
+
 fn main() {
   let args: Vec<String> = env::args().collect();
   let program = args[0].clone();
 
   let mut opts = Options::new();
 
-  opts.optopt("s", "store", "kv store to use", "STORE");
-  opts.optflag("n", "number", "enumerate list values");
+  for &(short, long, desc, hint) in GLOBAL_OPTS {
+    opts.optopt(short, long, desc, hint);
+  }
+
+  for &(short, long, desc) in GLOBAL_FLAGS {
+    opts.optflag(short, long, desc);
+  }
+
+  let (global, command) = split_global_options(&args[1..]);
 
-  let args = match opts.parse(&args[1..]) {
+  let args = match opts.parse(&global) {
     Ok(m) => m,
-    Err(f) => panic!(f.to_string())
+    Err(f) => return die(&f),
   };
 
-  let store_file = args.opt_str("s").unwrap_or(".kvs.json".to_string());
+  let backend = args.opt_str("backend").unwrap_or("json".to_string());
+
+  let store_file = match backend.as_str() {
+    "json" => args.opt_str("s").unwrap_or(".kvs.json".to_string()),
+    "dir" => args.opt_str("s").unwrap_or(".kvs".to_string()),
+    other => return die(&format!("unknown backend: {}", other)),
+  };
+
+  let lock_timeout = match args.opt_str("lock-timeout").map(|s| s.parse::<u64>()) {
+    Some(Ok(secs)) => Duration::from_secs(secs),
+    Some(Err(e)) => return die(&format!("invalid lock timeout: {}", e)),
+    None => Duration::from_secs(10),
+  };
+
+  let hook_timeout = match args.opt_str("hook-timeout").map(|s| s.parse::<u64>()) {
+    Some(Ok(0)) => None,
+    Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+    Some(Err(e)) => return die(&format!("invalid hook timeout: {}", e)),
+    None => Some(hooks::DEFAULT_TIMEOUT),
+  };
+
+  let storage_mode = match args.opt_str("compact-threshold").map(|s| s.parse::<u64>()) {
+    Some(Err(e)) => return die(&format!("invalid compact threshold: {}", e)),
+    _ if !args.opt_present("append-log") => StorageMode::Snapshot,
+    _ if backend != "json" => return die(&"--append-log requires the json backend"),
+    Some(Ok(bytes)) => StorageMode::AppendLog(bytes),
+    None => StorageMode::AppendLog(1024 * 1024),
+  };
 
   let store_file_clone = store_file.clone();
   let hooks_dir = path::Path::new(&store_file_clone).parent().unwrap_or(path::Path::new("./"));
 
-  let hooks = hooks::Hooks::load_from_dir(hooks_dir);
+  let mut hooks = match hooks::Hooks::load_from_dir(hooks_dir) {
+    Ok(hooks) => hooks,
+    Err(e) => return die(&e),
+  };
+  hooks.set_stop_on_failure(args.opt_present("stop-on-hook-failure"));
+  hooks.set_json_events(args.opt_present("json-hook-events"));
+  hooks.set_timeout(hook_timeout);
+
+  let enumerate_list = args.opt_present("n");
+
+  if backend == "dir" {
+    run(Ui::<DirBackend>::new(program, store_file, enumerate_list, hooks, lock_timeout, storage_mode), command)
+  } else {
+    run(Ui::<JsonFileBackend>::new(program, store_file, enumerate_list, hooks, lock_timeout, storage_mode), command)
+  }
+}
 
-  let ui = Ui::new(program, store_file, args.opt_present("n"), hooks);
+fn run<B: Backend + 'static>(ui: Ui<B>, args: Vec<String>) {
+  match args.first().map(String::as_str) {
+    Some("serve") => return serve(ui, &args[1..], false),
+    Some("http") => return serve(ui, &args[1..], true),
+    Some("shell") => return shell(ui, &args[1..]),
+    _ => (),
+  }
 
-  match ui.run(args.free) {
+  match ui.run(args) {
     Ok(UiResult::Ok) => (),
     Ok(result) => println!("{}", result),
     Err(err) => die(&err),
   };
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn split(line: &str) -> (Vec<String>, Vec<String>) {
+    let args: Vec<String> = line.split(' ').map(str::to_string).collect();
+
+    split_global_options(&args)
+  }
+
+  fn strings(v: &[&str]) -> Vec<String> {
+    v.iter().map(|s| s.to_string()).collect()
+  }
+
+  #[test]
+  fn test_split_global_options() {
+    assert_eq!((strings(&["-n"]), strings(&["ls"])), split("ls -n"));
+    assert_eq!((strings(&["-s", "other.json"]), strings(&["get", "k"])), split("get k -s other.json"));
+    assert_eq!((strings(&["--store=a.json", "-n"]), strings(&["put", "--ttl", "60", "k", "v"])), split("--store=a.json put --ttl 60 k v -n"));
+    assert_eq!((strings(&["--hook-timeout", "5"]), strings(&["serve", "--listen", "127.0.0.1:1", "-f", "never"])), split("serve --listen 127.0.0.1:1 --hook-timeout 5 -f never"));
+    assert_eq!((strings(&["-sother.json"]), strings(&["put", "k", "-n"])), split("-sother.json put k -- -n"));
+    assert_eq!((Vec::new(), strings(&["get", "--numbered", "--stores"])), split("get --numbered --stores"));
+  }
+}
@@ -0,0 +1,240 @@
+use std;
+use std::fs::{
+  self,
+  File,
+  OpenOptions,
+};
+use std::io::{
+  BufRead,
+  BufReader,
+  Read,
+  Seek,
+  SeekFrom,
+  Write,
+};
+use std::path::{
+  Path,
+  PathBuf,
+};
+
+use serde_json;
+
+use ::{
+  KVError,
+  KVStore,
+  Result,
+};
+
+use ::backend::Backend;
+
+use ::cmd::Command;
+
+/// A change command as recorded in the operation log.
+///
+/// `seq` numbers entries across compactions, so entries already folded into the snapshot
+/// are skipped on replay. `time` is when the command ran, so time to live values replay
+/// to the same expiry.
+#[derive(Serialize, Deserialize, Debug)]
+struct LogEntry {
+  seq: u64,
+  time: u64,
+  command: Command,
+}
+
+pub fn log_path_for(store_path: &Path) -> PathBuf {
+  let mut log_path = store_path.as_os_str().to_owned();
+  log_path.push(".log");
+  PathBuf::from(log_path)
+}
+
+/// Cuts off the remainder of an interrupted append, so the next entry starts on a fresh line.
+fn discard_partial_entry(file: &mut File) -> std::io::Result<()> {
+  let len = file.metadata()?.len();
+  if len == 0 {
+    return Ok(());
+  }
+
+  let mut last = [0; 1];
+  file.seek(SeekFrom::Start(len - 1))?;
+  file.read_exact(&mut last)?;
+
+  if last[0] != b'\n' {
+    let mut content = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut content)?;
+
+    let valid_len = content.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+    file.set_len(valid_len as u64)?;
+  }
+
+  file.seek(SeekFrom::End(0)).map(|_| ())
+}
+
+/// Appends `command` to the log at `log_path`. Returns the size of the log afterwards.
+pub fn append<B: Backend>(kvs: &mut KVStore<B>, log_path: &Path, command: &Command) -> Result<u64> {
+  let persist_error = |e| KVError::PersistError(format!("appending to {}", log_path.display()), e);
+
+  let entry = LogEntry {
+    seq: kvs.backend().log_seq() + 1,
+    time: ::unix_now(),
+    command: command.clone(),
+  };
+
+  let mut line = serde_json::to_string(&entry)?;
+  line.push('\n');
+
+  let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(log_path).map_err(&persist_error)?;
+  discard_partial_entry(&mut file).map_err(&persist_error)?;
+  file.write_all(line.as_bytes()).map_err(&persist_error)?;
+  file.sync_data().map_err(&persist_error)?;
+
+  kvs.backend_mut().set_log_seq(entry.seq);
+
+  Ok(file.metadata().map_err(&persist_error)?.len())
+}
+
+/// Applies the entries of the log at `log_path` that are newer than the snapshot.
+///
+/// A final line that cannot be parsed is the remainder of an interrupted append and is ignored.
+pub fn replay<B: Backend>(kvs: &mut KVStore<B>, log_path: &Path) -> Result<()> {
+  let file = match File::open(log_path) {
+    Ok(file) => file,
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+    Err(e) => return Err(e.into()),
+  };
+
+  let lines = BufReader::new(file).lines().collect::<std::io::Result<Vec<String>>>()?;
+
+  for (i, line) in lines.iter().enumerate() {
+    let entry: LogEntry = match serde_json::from_str(line) {
+      Ok(entry) => entry,
+      Err(_) if i == lines.len() - 1 => break,
+      Err(e) => return Err(KVError::CorruptLog(format!("{} line {}: {}", log_path.display(), i + 1, e))),
+    };
+
+    if entry.seq <= kvs.backend().log_seq() {
+      continue;
+    }
+
+    kvs.apply_at(&entry.command, entry.time)
+      .map_err(|e| KVError::CorruptLog(format!("{} entry {}: {}", log_path.display(), entry.seq, e)))?;
+    kvs.backend_mut().set_log_seq(entry.seq);
+  }
+
+  Ok(())
+}
+
+/// Removes the log at `log_path`, after its entries have been folded into the snapshot.
+pub fn remove(log_path: &Path) -> Result<()> {
+  match fs::remove_file(log_path) {
+    Ok(()) => Ok(()),
+    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+    Err(e) => Err(KVError::PersistError(format!("removing {}", log_path.display()), e)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use ::Value;
+  use ::test_util::TempDir;
+
+  fn temp_store(dir: &Path) -> PathBuf {
+    let path = dir.join(".kvs.json");
+    ::init(&path).unwrap();
+    path
+  }
+
+  fn log_change(kvs: &mut KVStore, path: &Path, command: Command) {
+    kvs.apply_at(&command, ::unix_now()).unwrap();
+    append(kvs, &log_path_for(path), &command).unwrap();
+  }
+
+  #[test]
+  fn test_replay_log() {
+    let dir = TempDir::new("oplog-replay");
+    let path = temp_store(&dir);
+    let mut kvs = KVStore::read_from_file(&path).unwrap();
+
+    log_change(&mut kvs, &path, Command::CreateEmptyList("list".to_string()));
+    log_change(&mut kvs, &path, Command::PushListValue("list".to_string(), "a b".to_string()));
+    log_change(&mut kvs, &path, Command::PutStringWithTtl("token".to_string(), "secret".to_string(), 3600));
+
+    let read = KVStore::read_from_file(&path).unwrap();
+
+    match read.get(&"list") {
+      Some(Value::ListValue(list)) => assert_eq!(vec!["a b".to_string()], *list),
+      other => panic!("unexpected value {:?}", other),
+    }
+    assert!(read.get_ttl(&"token").is_some());
+    assert_eq!(3, read.backend().log_seq());
+  }
+
+  #[test]
+  fn test_replay_judges_expiry_at_entry_time() {
+    let dir = TempDir::new("oplog-expiry");
+    let path = temp_store(&dir);
+    let start = ::unix_now() - 100;
+    let counter = || "counter".to_string();
+
+    let entries = [
+      (start, Command::Increment(counter(), 1)),
+      (start, Command::Expire(counter(), 1)),
+      (start, Command::Increment(counter(), 1)),
+    ];
+    let log: Vec<String> = entries.iter().enumerate()
+      .map(|(i, &(time, ref command))| serde_json::to_string(&LogEntry { seq: i as u64 + 1, time: time, command: command.clone() }).unwrap() + "\n")
+      .collect();
+    fs::write(log_path_for(&path), log.concat()).unwrap();
+
+    // the counter reached 2 before it expired, like it did when the commands ran
+    let mut kvs = KVStore::read_from_file(&path).unwrap();
+    assert!(!kvs.has_key("counter"));
+
+    kvs.apply_at(&Command::Increment(counter(), 1), start + 5).unwrap();
+    assert_eq!(Some(&Value::IntValue(1)), kvs.get(&"counter"));
+    assert_eq!(None, kvs.get_ttl(&"counter"));
+  }
+
+  #[test]
+  fn test_snapshot_skips_folded_entries() {
+    let dir = TempDir::new("oplog-compact");
+    let path = temp_store(&dir);
+    let mut kvs = KVStore::read_from_file(&path).unwrap();
+
+    log_change(&mut kvs, &path, Command::CreateEmptyList("list".to_string()));
+    log_change(&mut kvs, &path, Command::PushListValue("list".to_string(), "a".to_string()));
+
+    let log = fs::read(log_path_for(&path)).unwrap();
+    kvs.flush().unwrap();
+    assert!(!log_path_for(&path).exists());
+
+    // a crash between writing the snapshot and removing the log must not apply entries twice
+    fs::write(log_path_for(&path), log).unwrap();
+
+    match KVStore::read_from_file(&path).unwrap().get(&"list") {
+      Some(Value::ListValue(list)) => assert_eq!(1, list.len()),
+      other => panic!("unexpected value {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_ignores_truncated_last_entry() {
+    let dir = TempDir::new("oplog-truncated");
+    let path = temp_store(&dir);
+    let mut kvs = KVStore::read_from_file(&path).unwrap();
+
+    log_change(&mut kvs, &path, Command::PutString("key".to_string(), "value".to_string()));
+
+    let mut file = OpenOptions::new().append(true).open(log_path_for(&path)).unwrap();
+    file.write_all(b"{\"seq\":2,\"ti").unwrap();
+
+    let mut read = KVStore::read_from_file(&path).unwrap();
+    assert!(read.has_key("key"));
+    assert_eq!(1, read.backend().log_seq());
+
+    log_change(&mut read, &path, Command::Drop("key".to_string()));
+    assert!(!KVStore::read_from_file(&path).unwrap().has_key("key"));
+  }
+}
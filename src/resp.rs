@@ -0,0 +1,536 @@
+use std;
+use std::fmt::Display;
+use std::io::{
+  self,
+  BufRead,
+  Read,
+  Write,
+};
+
+use ::cmd::Command;
+
+use ::glob;
+
+use ::KVError;
+
+use ::server::{
+  self,
+  Server,
+  MAX_LINE_LEN,
+};
+
+use ::backend::Backend;
+
+use ::ui::{
+  UiError,
+  UiResult,
+};
+
+/// A value of the Redis serialization protocol (RESP2).
+#[derive(Debug, PartialEq, Eq)]
+pub enum RespValue {
+  Simple(String),
+  Error(String),
+  Integer(i64),
+  Bulk(Option<String>),
+  Array(Vec<RespValue>),
+}
+
+type RespResult = std::result::Result<RespValue, String>;
+
+/// Longest bulk string a client may send, like the default `proto-max-bulk-len` of Redis.
+pub const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+
+/// Most elements an array sent by a client may have.
+pub const MAX_ARRAY_LEN: i64 = 1024 * 1024;
+
+/// How deep arrays sent by a client may be nested.
+pub const MAX_DEPTH: usize = 8;
+
+fn invalid_data<S: ToString>(msg: S) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+  let mut line = String::new();
+
+  if server::read_line_limited(reader, &mut line, MAX_LINE_LEN)? == 0 {
+    return Ok(None);
+  }
+
+  let len = line.trim_end_matches(['\r', '\n']).len();
+  line.truncate(len);
+
+  Ok(Some(line))
+}
+
+fn parse_length(s: &str) -> io::Result<i64> {
+  s.parse().map_err(|_| invalid_data(format!("invalid length: {}", s)))
+}
+
+impl RespValue {
+  /// Reads the next value, or None if the stream has ended.
+  ///
+  /// Values larger than `MAX_BULK_LEN`, `MAX_ARRAY_LEN` or `MAX_DEPTH`, and lines longer than
+  /// `MAX_LINE_LEN`, are refused with an `InvalidData` error before anything is allocated for them.
+  pub fn read_from<R: BufRead>(reader: &mut R) -> io::Result<Option<RespValue>> {
+    RespValue::read_nested(reader, 0)
+  }
+
+  fn read_nested<R: BufRead>(reader: &mut R, depth: usize) -> io::Result<Option<RespValue>> {
+    let line = match read_line(reader)? {
+      Some(line) => line,
+      None => return Ok(None),
+    };
+
+    if line.is_empty() {
+      return Err(invalid_data("empty line"));
+    }
+
+    let (marker, rest) = line.split_at(1);
+
+    let value = match marker {
+      "+" => RespValue::Simple(rest.to_string()),
+      "-" => RespValue::Error(rest.to_string()),
+      ":" => RespValue::Integer(parse_length(rest)?),
+      "$" => {
+        let len = parse_length(rest)?;
+
+        if len < 0 {
+          RespValue::Bulk(None)
+        } else if len > MAX_BULK_LEN {
+          return Err(invalid_data("invalid bulk length"));
+        } else {
+          // grows with the data actually received rather than with the announced length
+          let mut buf = Vec::new();
+          reader.by_ref().take(len as u64 + 2).read_to_end(&mut buf)?;
+
+          if buf.len() as i64 != len + 2 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "unexpected end of bulk string"));
+          }
+
+          if !buf.ends_with(b"\r\n") {
+            return Err(invalid_data("bulk string not terminated by CRLF"));
+          }
+
+          buf.truncate(len as usize);
+          RespValue::Bulk(Some(String::from_utf8(buf).map_err(invalid_data)?))
+        }
+      },
+      "*" => {
+        let len = parse_length(rest)?;
+
+        if len > MAX_ARRAY_LEN {
+          return Err(invalid_data("invalid multibulk length"));
+        }
+
+        if depth >= MAX_DEPTH {
+          return Err(invalid_data("arrays nested too deeply"));
+        }
+
+        let mut values = Vec::new();
+
+        for _ in 0..len {
+          values.push(RespValue::read_nested(reader, depth + 1)?.ok_or(invalid_data("unexpected end of array"))?);
+        }
+
+        RespValue::Array(values)
+      },
+      _ => return Err(invalid_data(format!("unknown type marker {}", marker))),
+    };
+
+    Ok(Some(value))
+  }
+
+  pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+    match *self {
+      RespValue::Simple(ref s) => write!(w, "+{}\r\n", s),
+      RespValue::Error(ref s) => write!(w, "-{}\r\n", s.replace(['\r', '\n'], " ")),
+      RespValue::Integer(i) => write!(w, ":{}\r\n", i),
+      RespValue::Bulk(Some(ref s)) => write!(w, "${}\r\n{}\r\n", s.len(), s),
+      RespValue::Bulk(None) => write!(w, "$-1\r\n"),
+      RespValue::Array(ref values) => {
+        write!(w, "*{}\r\n", values.len())?;
+        for value in values {
+          value.write_to(w)?;
+        }
+        Ok(())
+      },
+    }
+  }
+}
+
+/// Reads the next request, either a RESP array of bulk strings or an inline command line.
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<String>>> {
+  let is_array = match reader.fill_buf()?.first() {
+    Some(&b'*') => true,
+    Some(_) => false,
+    None => return Ok(None),
+  };
+
+  if !is_array {
+    return Ok(read_line(reader)?.map(|line| line.split_whitespace().map(ToString::to_string).collect()));
+  }
+
+  match RespValue::read_from(reader)? {
+    Some(RespValue::Array(values)) => values.into_iter()
+      .map(|value| match value {
+        RespValue::Bulk(Some(s)) => Ok(s),
+        other => Err(invalid_data(format!("expected bulk string, got {:?}", other))),
+      })
+      .collect::<io::Result<Vec<String>>>()
+      .map(Some),
+    _ => Err(invalid_data("expected array")),
+  }
+}
+
+pub fn handle_connection<B: Backend, R: BufRead, W: Write>(server: &Server<B>, mut reader: R, mut writer: W) -> std::result::Result<(), UiError> {
+  loop {
+    let request = match read_request(&mut reader) {
+      Ok(Some(request)) => request,
+      Ok(None) => break,
+      Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+        // the rest of the stream cannot be made sense of, so the connection is closed like Redis does
+        RespValue::Error(format!("ERR Protocol error: {}", e)).write_to(&mut writer)?;
+        writer.flush()?;
+        break;
+      },
+      Err(e) => return Err(e.into()),
+    };
+
+    if request.is_empty() {
+      continue;
+    }
+
+    let is_quit = request[0].eq_ignore_ascii_case("QUIT");
+
+    let reply = if is_quit {
+      RespValue::Simple("OK".to_string())
+    } else {
+      handle_request(server, &request)
+    };
+
+    reply.write_to(&mut writer)?;
+    writer.flush()?;
+
+    if is_quit {
+      break;
+    }
+  }
+
+  Ok(())
+}
+
+fn error<D: Display>(e: D) -> String {
+  format!("ERR {}", e)
+}
+
+fn wrong_type() -> String {
+  "WRONGTYPE Operation against a key holding the wrong kind of value".to_string()
+}
+
+fn check_arity(name: &str, args: &[String], min: usize, max: Option<usize>) -> std::result::Result<(), String> {
+  if args.len() < min || max.is_some_and(|max| args.len() > max) {
+    Err(error(format!("wrong number of arguments for '{}' command", name.to_lowercase())))
+  } else {
+    Ok(())
+  }
+}
+
+/// Maps a single Redis request onto the matching `Command`s and executes them on `server`.
+pub fn handle_request<B: Backend>(server: &Server<B>, request: &[String]) -> RespValue {
+  let name = request[0].to_uppercase();
+  let args = &request[1..];
+
+  let result = match name.as_str() {
+    "PING" => check_arity(&name, args, 0, Some(1)).map(|_| match args.first() {
+      Some(message) => RespValue::Bulk(Some(message.clone())),
+      None => RespValue::Simple("PONG".to_string()),
+    }),
+    "COMMAND" => Ok(RespValue::Array(Vec::new())),
+    "GET" => check_arity(&name, args, 1, Some(1)).and_then(|_| get(server, &args[0])),
+    "SET" => check_arity(&name, args, 2, Some(4)).and_then(|_| set(server, args)),
+    "DEL" => check_arity(&name, args, 1, None).and_then(|_| del(server, args)),
+    "EXISTS" => check_arity(&name, args, 1, None).and_then(|_| exists(server, args)),
+    "LPUSH" => check_arity(&name, args, 2, None).and_then(|_| lpush(server, &args[0], &args[1..])),
+    "RPOP" => check_arity(&name, args, 1, Some(1)).and_then(|_| rpop(server, &args[0])),
+    "KEYS" => check_arity(&name, args, 1, Some(1)).and_then(|_| keys(server, &args[0])),
+    "INCR" => check_arity(&name, args, 1, Some(1)).and_then(|_| incr(server, &args[0])),
+    _ => Err(error(format!("unknown command '{}'", request[0]))),
+  };
+
+  result.unwrap_or_else(RespValue::Error)
+}
+
+fn execute<B: Backend>(server: &Server<B>, command: Command) -> std::result::Result<UiResult, String> {
+  server.execute_command(&command).map_err(error)
+}
+
+fn get<B: Backend>(server: &Server<B>, key: &str) -> RespResult {
+  match server.execute_command(&Command::Get(key.to_string())) {
+    Ok(UiResult::StringValueResult(value)) => Ok(RespValue::Bulk(Some(value))),
+    Ok(_) => Err(wrong_type()),
+    Err(UiError::NoValueForKey(_)) => Ok(RespValue::Bulk(None)),
+    Err(e) => Err(error(e)),
+  }
+}
+
+fn set<B: Backend>(server: &Server<B>, args: &[String]) -> RespResult {
+  let command = match args.len() {
+    2 => Command::PutString(args[0].clone(), args[1].clone()),
+    4 if args[2].eq_ignore_ascii_case("EX") => {
+      let ttl = args[3].parse().map_err(|_| error("value is not an integer or out of range"))?;
+      Command::PutStringWithTtl(args[0].clone(), args[1].clone(), ttl)
+    },
+    _ => return Err(error("syntax error")),
+  };
+
+  execute(server, command).map(|_| RespValue::Simple("OK".to_string()))
+}
+
+fn del<B: Backend>(server: &Server<B>, keys: &[String]) -> RespResult {
+  let mut count = 0;
+
+  for key in keys {
+    match server.execute_command(&Command::Drop(key.clone())) {
+      Ok(_) => count += 1,
+      Err(UiError::NoValueForKey(_)) => (),
+      Err(e) => return Err(error(e)),
+    }
+  }
+
+  Ok(RespValue::Integer(count))
+}
+
+// Looks at the store directly rather than through `Command::Get`, which would run the onMiss hooks.
+fn exists<B: Backend>(server: &Server<B>, keys: &[String]) -> RespResult {
+  let count = server.with_store(|kvs| keys.iter().filter(|key| kvs.get(key).is_some()).count());
+
+  Ok(RespValue::Integer(count as i64))
+}
+
+fn lpush<B: Backend>(server: &Server<B>, key: &str, values: &[String]) -> RespResult {
+  match server.execute_command(&Command::PushFrontListValues(key.to_string(), values.to_vec())) {
+    Ok(UiResult::StringValueResult(len)) => len.parse().map(RespValue::Integer).map_err(error),
+    Ok(_) | Err(UiError::KvError(KVError::WrongType(..))) => Err(wrong_type()),
+    Err(e) => Err(error(e)),
+  }
+}
+
+fn rpop<B: Backend>(server: &Server<B>, key: &str) -> RespResult {
+  match server.execute_command(&Command::PopListValue(key.to_string())) {
+    Ok(UiResult::StringValueResult(value)) => Ok(RespValue::Bulk(Some(value))),
+    Ok(_) | Err(UiError::KvError(KVError::WrongType(..))) => Err(wrong_type()),
+    Err(UiError::NoValueForKey(_)) | Err(UiError::KvError(KVError::EmptyList(_))) => Ok(RespValue::Bulk(None)),
+    Err(e) => Err(error(e)),
+  }
+}
+
+fn keys<B: Backend>(server: &Server<B>, pattern: &str) -> RespResult {
+  match execute(server, Command::ListKeys)? {
+    UiResult::StringListResult(keys) => {
+      let mut keys: Vec<String> = keys.into_iter().filter(|key| glob::matches(pattern, key)).collect();
+      keys.sort();
+
+      Ok(RespValue::Array(keys.into_iter().map(|key| RespValue::Bulk(Some(key))).collect()))
+    },
+    _ => Ok(RespValue::Array(Vec::new())),
+  }
+}
+
+fn incr<B: Backend>(server: &Server<B>, key: &str) -> RespResult {
+  match execute(server, Command::Increment(key.to_string(), 1))? {
+    UiResult::StringValueResult(value) => value.parse().map(RespValue::Integer).map_err(error),
+    _ => Err(wrong_type()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs;
+  use std::io::BufReader;
+  use std::net::{
+    TcpListener,
+    TcpStream,
+  };
+  use std::os::unix::fs::PermissionsExt;
+  use std::thread;
+  use std::time::Duration;
+
+  use ::hooks::Hooks;
+  use ::server::FlushPolicy;
+  use ::test_util::TempDir;
+  use ::ui::Ui;
+
+  struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    _dir: TempDir,
+  }
+
+  impl Client {
+    fn call(&mut self, args: &[&str]) -> RespValue {
+      let request = RespValue::Array(args.iter().map(|a| RespValue::Bulk(Some(a.to_string()))).collect());
+      request.write_to(&mut self.writer).unwrap();
+
+      RespValue::read_from(&mut self.reader).unwrap().unwrap()
+    }
+  }
+
+  fn start_server(name: &str) -> Client {
+    start_server_with_hooks(name, &[])
+  }
+
+  fn start_server_with_hooks(name: &str, hooks: &[(&str, &str)]) -> Client {
+    let dir = TempDir::new(&format!("resp-{}", name));
+
+    for (hook, script) in hooks {
+      fs::write(dir.join(hook), format!("#!/usr/bin/env bash\n\n{}\n", script)).unwrap();
+      fs::set_permissions(dir.join(hook), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    let path = dir.join(".kvs.json");
+    ::init(&path).unwrap();
+
+    let ui: Ui = Ui::new("kvs2".to_string(), path.to_str().unwrap().to_string(), false, Hooks::load_from_dir(&dir).unwrap(), Duration::from_secs(1), ::StorageMode::Snapshot);
+    let server = Server::new(ui, FlushPolicy::Always).unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve(listener));
+
+    let stream = TcpStream::connect(addr).unwrap();
+    Client {
+      reader: BufReader::new(stream.try_clone().unwrap()),
+      writer: stream,
+      _dir: dir,
+    }
+  }
+
+  fn bulk(s: &str) -> RespValue {
+    RespValue::Bulk(Some(s.to_string()))
+  }
+
+  #[test]
+  fn test_limits() {
+    let read = |bytes: &str| RespValue::read_from(&mut BufReader::new(bytes.as_bytes()));
+
+    assert_eq!(io::ErrorKind::InvalidData, read("$9999999999999\r\n").unwrap_err().kind());
+    assert_eq!(io::ErrorKind::InvalidData, read("*9999999999999\r\n").unwrap_err().kind());
+    assert_eq!(io::ErrorKind::UnexpectedEof, read("$5\r\nab").unwrap_err().kind());
+    assert_eq!(io::ErrorKind::InvalidData, read(&"*1\r\n".repeat(MAX_DEPTH + 1)).unwrap_err().kind());
+    assert_eq!(io::ErrorKind::InvalidData, read(&format!("+{}\r\n", "a".repeat(MAX_LINE_LEN))).unwrap_err().kind());
+    assert_eq!(bulk("ab"), read("$2\r\nab\r\n").unwrap().unwrap());
+
+    let inline = format!("SET key {}\r\n", "a".repeat(MAX_LINE_LEN));
+    assert_eq!(io::ErrorKind::InvalidData, read_request(&mut BufReader::new(inline.as_bytes())).unwrap_err().kind());
+  }
+
+  #[test]
+  fn test_protocol_error_reply() {
+    let mut client = start_server("protocol");
+
+    client.writer.write_all(b"*1\r\n$9999999999999\r\n").unwrap();
+    match RespValue::read_from(&mut client.reader).unwrap() {
+      Some(RespValue::Error(ref e)) => assert!(e.starts_with("ERR Protocol error")),
+      other => panic!("unexpected reply {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_strings() {
+    let mut client = start_server("strings");
+
+    assert_eq!(RespValue::Simple("PONG".to_string()), client.call(&["PING"]));
+    assert_eq!(RespValue::Simple("OK".to_string()), client.call(&["SET", "key", "some value"]));
+    assert_eq!(bulk("some value"), client.call(&["get", "key"]));
+    assert_eq!(RespValue::Bulk(None), client.call(&["GET", "missing"]));
+    assert_eq!(RespValue::Integer(1), client.call(&["EXISTS", "key", "missing"]));
+    assert_eq!(RespValue::Integer(1), client.call(&["DEL", "key", "missing"]));
+    assert_eq!(RespValue::Integer(0), client.call(&["EXISTS", "key"]));
+  }
+
+  #[test]
+  fn test_lists() {
+    let mut client = start_server("lists");
+
+    assert_eq!(RespValue::Integer(2), client.call(&["LPUSH", "queue", "a", "b"]));
+    assert_eq!(RespValue::Integer(3), client.call(&["LPUSH", "queue", "c"]));
+    assert_eq!(bulk("a"), client.call(&["RPOP", "queue"]));
+    assert_eq!(bulk("b"), client.call(&["RPOP", "queue"]));
+    assert_eq!(bulk("c"), client.call(&["RPOP", "queue"]));
+    assert_eq!(RespValue::Bulk(None), client.call(&["RPOP", "queue"]));
+    assert_eq!(RespValue::Bulk(None), client.call(&["RPOP", "missing"]));
+  }
+
+  #[test]
+  fn test_on_miss_hook_only_for_get() {
+    let mut client = start_server_with_hooks("miss", &[(".onMiss", "echo \"fetched $2\"")]);
+
+    assert_eq!(bulk("fetched host"), client.call(&["GET", "host"]));
+    assert_eq!(RespValue::Integer(0), client.call(&["EXISTS", "host"]));
+    assert_eq!(RespValue::Integer(1), client.call(&["LPUSH", "queue", "a"]));
+    assert_eq!(bulk("a"), client.call(&["RPOP", "queue"]));
+    assert_eq!(RespValue::Bulk(None), client.call(&["RPOP", "missing"]));
+  }
+
+  #[test]
+  fn test_failed_hook_keeps_result() {
+    let mut client = start_server_with_hooks("failed-hook", &[(".postChange", "exit 1")]);
+
+    assert_eq!(RespValue::Integer(1), client.call(&["LPUSH", "queue", "a"]));
+    assert_eq!(bulk("a"), client.call(&["RPOP", "queue"]));
+    assert_eq!(RespValue::Simple("OK".to_string()), client.call(&["SET", "key", "value"]));
+  }
+
+  #[test]
+  fn test_incr_and_keys() {
+    let mut client = start_server("keys");
+
+    assert_eq!(RespValue::Integer(1), client.call(&["INCR", "build.number"]));
+    assert_eq!(RespValue::Integer(2), client.call(&["INCR", "build.number"]));
+    client.call(&["SET", "build.branch", "main"]);
+    client.call(&["SET", "other", "value"]);
+
+    assert_eq!(RespValue::Array(vec![bulk("build.branch"), bulk("build.number")]), client.call(&["KEYS", "build.*"]));
+
+    match client.call(&["GET", "build.number"]) {
+      RespValue::Bulk(Some(ref value)) => assert_eq!("2", value),
+      other => panic!("unexpected reply {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_errors() {
+    let mut client = start_server("errors");
+
+    client.call(&["LPUSH", "queue", "a"]);
+
+    match client.call(&["GET", "queue"]) {
+      RespValue::Error(ref msg) => assert!(msg.starts_with("WRONGTYPE")),
+      other => panic!("unexpected reply {:?}", other),
+    }
+
+    client.call(&["SET", "name", "a"]);
+
+    for args in &[&["LPUSH", "name", "b"][..], &["RPOP", "name"][..]] {
+      match client.call(args) {
+        RespValue::Error(ref msg) => assert!(msg.starts_with("WRONGTYPE")),
+        other => panic!("unexpected reply {:?}", other),
+      }
+    }
+    assert_eq!(bulk("a"), client.call(&["GET", "name"]));
+
+    match client.call(&["FLUSHALL"]) {
+      RespValue::Error(ref msg) => assert!(msg.starts_with("ERR unknown command")),
+      other => panic!("unexpected reply {:?}", other),
+    }
+
+    match client.call(&["GET"]) {
+      RespValue::Error(ref msg) => assert!(msg.starts_with("ERR wrong number of arguments")),
+      other => panic!("unexpected reply {:?}", other),
+    }
+  }
+}
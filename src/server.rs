@@ -0,0 +1,597 @@
+use std;
+use std::io::{
+  self,
+  BufRead,
+  BufReader,
+  BufWriter,
+  Read,
+  Write,
+};
+use std::net::{
+  TcpListener,
+  TcpStream,
+};
+use std::process;
+use std::str::FromStr;
+use std::sync::{
+  Arc,
+  Mutex,
+  MutexGuard,
+};
+use std::thread;
+use std::time::Duration;
+
+use ::KVStore;
+
+use ::backend::{
+  Backend,
+  JsonFileBackend,
+};
+
+use ::cmd::Command;
+
+use ::listener::ChangeEvent;
+
+use ::resp;
+
+use ::lock::{
+  LockMode,
+  StoreLock,
+};
+
+use ::ui::{
+  Resolved,
+  Ui,
+  UiError,
+  UiResult,
+};
+
+type Result<T> = std::result::Result<T, UiError>;
+
+/// Longest request line a client may send, which is refused rather than buffered.
+pub const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Reads a line like `BufRead::read_line`, but fails with `InvalidData` instead of reading on
+/// once the line is longer than `limit` bytes.
+pub fn read_line_limited<R: BufRead>(reader: &mut R, line: &mut String, limit: usize) -> io::Result<usize> {
+  let mut bytes = Vec::new();
+  let read = reader.by_ref().take(limit as u64 + 1).read_until(b'\n', &mut bytes)?;
+
+  if read > limit {
+    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line longer than {} bytes", limit)));
+  }
+
+  line.push_str(&String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+  Ok(read)
+}
+
+/// How long a connection may stay silent before it is closed.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often the hooks for a command run before the server gives up on a key that keeps changing.
+pub const MAX_HOOK_RUNS: usize = 8;
+
+/// When a server writes its in-memory store back to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+  /// Write the store after every change.
+  Always,
+  /// Write the store periodically, if it has changed.
+  Interval(Duration),
+  /// Only write the store when a client sends `save`.
+  Never,
+}
+
+impl FromStr for FlushPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> std::result::Result<Self, String> {
+    match s {
+      "always" => Ok(FlushPolicy::Always),
+      "never" => Ok(FlushPolicy::Never),
+      secs => secs.parse::<u64>()
+        .map(|secs| FlushPolicy::Interval(Duration::from_secs(secs)))
+        .map_err(|_| format!("invalid flush policy: {}", secs)),
+    }
+  }
+}
+
+struct State<B: Backend> {
+  kvs: KVStore<B>,
+  dirty: bool,
+}
+
+/// A long running process serving one store over a line based TCP protocol.
+///
+/// Each request is a single line in the grammar of `Command::from_str`. A successful reply is
+/// `OK <n>` followed by the `n` lines of the result, a failed one is a single `ERR <message>` line.
+/// If the change was saved but a postChange hook failed, the `OK` reply is preceded by a
+/// `WARN <message>` line. Besides the store commands, `save` flushes the store and `quit` closes the connection.
+/// A line longer than `MAX_LINE_LEN` is answered with `ERR` and closes the connection, as does
+/// silence for `READ_TIMEOUT`.
+/// Connections starting with a RESP array are served with the Redis protocol instead, see `resp`.
+///
+/// The onMiss and preChange hooks run without holding the in-memory store, so that a slow hook
+/// only holds up its own client. If another client changed the key in the meantime, the hooks
+/// run again for the new value before the command is applied, up to `MAX_HOOK_RUNS` times.
+pub struct Server<B: Backend = JsonFileBackend> {
+  ui: Ui<B>,
+  flush_policy: FlushPolicy,
+  state: Mutex<State<B>>,
+  _lock: StoreLock,
+}
+
+fn format_reply(result: &Result<(UiResult, Option<UiError>)>) -> String {
+  match *result {
+    Ok((ref result, ref warning)) => {
+      let text = result.to_string();
+      let warning = warning.as_ref().map_or(String::new(), |w| format!("WARN {}\n", w.to_string().replace('\n', " ")));
+      format!("{}OK {}\n{}\n", warning, text.split('\n').count(), text)
+    },
+    Err(ref e) => format!("ERR {}\n", e.to_string().replace('\n', " ")),
+  }
+}
+
+impl<B: Backend> Server<B> {
+  /// Loads the store of `ui` and keeps it locked for as long as the server lives.
+  pub fn new(ui: Ui<B>, flush_policy: FlushPolicy) -> Result<Server<B>> {
+    let lock = StoreLock::acquire(ui.store_path(), LockMode::Exclusive, ui.lock_timeout())?;
+    let kvs = ui.open_kvstore()?;
+
+    Ok(Server {
+      ui: ui,
+      flush_policy: flush_policy,
+      state: Mutex::new(State { kvs: kvs, dirty: false }),
+      _lock: lock,
+    })
+  }
+
+  pub fn serve(self, listener: TcpListener) -> Result<()> where B: 'static {
+    self.serve_with(listener, Server::handle_connection)
+  }
+
+  /// Accepts connections on `listener` and handles each one with `handler` in its own thread.
+  pub fn serve_with<F>(self, listener: TcpListener, handler: F) -> Result<()>
+    where F: Fn(&Server<B>, TcpStream) -> Result<()> + Send + Sync + 'static, B: 'static {
+    let server = Arc::new(self);
+    let handler = Arc::new(handler);
+
+    // before any other thread is started, as they inherit the blocked signals
+    Server::spawn_shutdown_handler(&server);
+    Server::spawn_flusher(&server);
+
+    for stream in listener.incoming() {
+      match stream {
+        Ok(stream) => {
+          if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+            eprintln!("Error: {}", e);
+            continue;
+          }
+
+          let server = server.clone();
+          let handler = handler.clone();
+          thread::spawn(move || {
+            match handler(&server, stream) {
+              // a client that went quiet is simply dropped
+              Err(UiError::IoError(ref e)) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {},
+              Err(e) => eprintln!("Error: {}", e),
+              Ok(()) => {},
+            }
+          });
+        },
+        Err(e) => eprintln!("Error: {}", e),
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Writes the store of `server` in the background if its flush policy is an interval.
+  pub fn spawn_flusher(server: &Arc<Server<B>>) where B: 'static {
+    if let FlushPolicy::Interval(interval) = server.flush_policy {
+      let flusher = server.clone();
+      thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = flusher.flush() {
+          eprintln!("Error: {}", e);
+        }
+      });
+    }
+  }
+
+  /// Writes the store of `server` and exits once the process gets SIGINT, SIGTERM or SIGHUP, so
+  /// that changes kept in memory by the `Never` and `Interval` flush policies are not lost.
+  /// Must be called before any other thread is started.
+  #[cfg(unix)]
+  pub fn spawn_shutdown_handler(server: &Arc<Server<B>>) where B: 'static {
+    let signals = unsafe {
+      let mut signals: libc::sigset_t = std::mem::zeroed();
+      libc::sigemptyset(&mut signals);
+      libc::sigaddset(&mut signals, libc::SIGINT);
+      libc::sigaddset(&mut signals, libc::SIGTERM);
+      libc::sigaddset(&mut signals, libc::SIGHUP);
+
+      // blocked before the flusher and connection threads are started, which inherit the mask,
+      // so that only the `sigwait` below receives them
+      libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+      signals
+    };
+
+    let server = server.clone();
+    thread::spawn(move || {
+      let mut signal = 0;
+      if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+        return;
+      }
+
+      if let Err(e) = server.flush() {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+      }
+
+      process::exit(128 + signal);
+    });
+  }
+
+  #[cfg(not(unix))]
+  pub fn spawn_shutdown_handler(_: &Arc<Server<B>>) {
+  }
+
+  fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    if reader.fill_buf()?.first() == Some(&b'*') {
+      return resp::handle_connection(self, reader, writer);
+    }
+
+    loop {
+      let mut line = String::new();
+
+      match read_line_limited(&mut reader, &mut line, MAX_LINE_LEN) {
+        Ok(0) => break,
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+          // the rest of the line cannot be told from the next request, so the connection is closed
+          writer.write_all(format!("ERR {}\n", e).as_bytes())?;
+          writer.flush()?;
+          break;
+        },
+        Err(e) => return Err(e.into()),
+      }
+
+      let line = line.trim_end_matches(['\r', '\n']);
+
+      if line.is_empty() {
+        continue;
+      }
+
+      if line == "quit" {
+        break;
+      }
+
+      writer.write_all(self.handle_line(line).as_bytes())?;
+      writer.flush()?;
+    }
+
+    Ok(())
+  }
+
+  /// Executes a single request line and returns the formatted reply.
+  pub fn handle_line(&self, line: &str) -> String {
+    format_reply(&self.execute(line))
+  }
+
+  /// Executes a single request line, which is either `save` or a store command. Returns the result
+  /// along with the error of a postChange hook, which failed after the change was saved.
+  pub fn execute(&self, line: &str) -> Result<(UiResult, Option<UiError>)> {
+    if line == "save" {
+      return self.flush().map(|_| (UiResult::Ok, None));
+    }
+
+    self.execute_command_with_warning(&Command::from_str(line)?)
+  }
+
+  /// Applies `command` to the in-memory store, flushing and running hooks as configured.
+  /// A failure of the listeners once the change has been saved is logged as a warning, as the
+  /// change cannot be taken back and the client needs its result, like the value of a `pop`.
+  pub fn execute_command(&self, command: &Command) -> Result<UiResult> {
+    let (result, warning) = self.execute_command_with_warning(command)?;
+
+    if let Some(warning) = warning {
+      eprintln!("Warning: {}", warning);
+    }
+
+    Ok(result)
+  }
+
+  /// Like `execute_command`, but a failure of the postChange hooks or other change listeners,
+  /// which run once the change has been saved, is returned next to the result.
+  pub fn execute_command_with_warning(&self, command: &Command) -> Result<(UiResult, Option<UiError>)> {
+    if let Command::Init(_) = *command {
+      return Err(UiError::InitWithExistingKvStore(self.ui.store_path().display().to_string()));
+    }
+
+    for _ in 0..MAX_HOOK_RUNS {
+      let (command, cached, mut event) = match self.run_hooks_before(command)? {
+        (Resolved::Run(command, cached), event) => (command, cached, event),
+        (Resolved::Answered(result), _) => return Ok((result, None)),
+      };
+      let mut state = self.lock_state();
+
+      if self.ui.misses(&state.kvs, &command) || Server::changed_since(&state.kvs, event.as_ref()) {
+        continue;
+      }
+
+      let result = self.ui.interpret(&mut state.kvs, &command)?;
+
+      if command.is_change() {
+        state.dirty = true;
+
+        if self.flush_policy == FlushPolicy::Always {
+          self.ui.save(&mut state.kvs, &command)?;
+          state.dirty = false;
+        }
+      }
+
+      self.ui.finish_change_event(&state.kvs, &mut event);
+      drop(state);
+
+      let warning = self.ui.notify_listeners(&result, event.as_ref()).err();
+
+      return Ok((cached.unwrap_or(result), warning));
+    }
+
+    Err(UiError::Contended(command.key().unwrap_or_default().to_string()))
+  }
+
+  /// Asks the onMiss hooks about a missing key and runs the preChange hooks for `command`, holding
+  /// the in-memory store only to look at it. Returns what to do about the command and its event.
+  fn run_hooks_before(&self, command: &Command) -> Result<(Resolved, Option<ChangeEvent>)> {
+    let resolved = if self.with_store(|kvs| self.ui.misses(kvs, command)) {
+      self.ui.fetch_missing(command.clone())?
+    } else {
+      Resolved::Run(command.clone(), None)
+    };
+
+    let event = match resolved {
+      Resolved::Run(ref command, _) => {
+        let event = self.with_store(|kvs| self.ui.change_event(kvs, command));
+        self.ui.run_pre_hooks(command, event.as_ref())?;
+        event
+      },
+      Resolved::Answered(_) => None,
+    };
+
+    Ok((resolved, event))
+  }
+
+  /// Whether the value at the key of `event` is no longer the one the hooks were shown.
+  fn changed_since(kvs: &KVStore<B>, event: Option<&ChangeEvent>) -> bool {
+    match event {
+      Some(&ChangeEvent { key: Some(ref key), ref old_value, secret, .. }) => kvs.get(key) != old_value.as_ref() || kvs.is_secret(key) != secret,
+      _ => false,
+    }
+  }
+
+  /// Gives `f` read access to the in-memory store.
+  pub fn with_store<T, F: FnOnce(&KVStore<B>) -> T>(&self, f: F) -> T {
+    f(&self.lock_state().kvs)
+  }
+
+  /// Writes the store to disk if it has changed since the last flush.
+  pub fn flush(&self) -> Result<()> {
+    let mut state = self.lock_state();
+    self.write(&mut state)
+  }
+
+  fn write(&self, state: &mut State<B>) -> Result<()> {
+    if state.dirty {
+      state.kvs.flush()?;
+      state.dirty = false;
+    }
+
+    Ok(())
+  }
+
+  fn lock_state(&self) -> MutexGuard<'_, State<B>> {
+    self.state.lock().unwrap_or_else(|e| e.into_inner())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs;
+  use std::path::{
+    Path,
+    PathBuf,
+  };
+  use std::os::unix::fs::PermissionsExt;
+  use std::time::Instant;
+
+  use ::hooks::Hooks;
+  use ::test_util::TempDir;
+
+  fn temp_store(dir: &Path) -> PathBuf {
+    let path = dir.join(".kvs.json");
+    ::init(&path).unwrap();
+    path
+  }
+
+  fn server(path: &Path, flush_policy: FlushPolicy) -> Server {
+    let ui = Ui::new("kvs2".to_string(), path.to_str().unwrap().to_string(), false, Hooks::load_from_dir(path.parent().unwrap()).unwrap(), Duration::from_secs(1), ::StorageMode::Snapshot);
+    Server::new(ui, flush_policy).unwrap()
+  }
+
+  #[test]
+  fn test_handle_line() {
+    let dir = TempDir::new("server-lines");
+    let path = temp_store(&dir);
+    let server = server(&path, FlushPolicy::Always);
+
+    assert_eq!("OK 1\nok\n", server.handle_line("put key some value"));
+    assert_eq!("OK 1\nsome value\n", server.handle_line("get key"));
+    assert_eq!("OK 1\nok\n", server.handle_line("emptyList list"));
+    assert_eq!("OK 1\nok\n", server.handle_line("push list a"));
+    assert_eq!("OK 1\nok\n", server.handle_line("push list b"));
+    assert_eq!("OK 2\na\nb\n", server.handle_line("get list"));
+    assert_eq!("ERR no value for key missing\n", server.handle_line("get missing"));
+    assert!(server.handle_line("init").starts_with("ERR "));
+
+    let kvs = KVStore::read_from_file(&path).unwrap();
+    assert!(kvs.has_key("list"));
+  }
+
+  #[test]
+  fn test_flush_never_waits_for_save() {
+    let dir = TempDir::new("server-never");
+    let path = temp_store(&dir);
+    let server = server(&path, FlushPolicy::Never);
+
+    server.handle_line("put key value");
+    assert!(!KVStore::read_from_file(&path).unwrap().has_key("key"));
+
+    assert_eq!("OK 1\nok\n", server.handle_line("save"));
+    assert!(KVStore::read_from_file(&path).unwrap().has_key("key"));
+  }
+
+  #[test]
+  fn test_serve_over_tcp() {
+    let dir = TempDir::new("server-tcp");
+    let path = temp_store(&dir);
+    let server = server(&path, FlushPolicy::Always);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve(listener));
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    writer.write_all(b"incr counter 5\r\nget counter\nquit\n").unwrap();
+
+    let mut reply = String::new();
+    for _ in 0..4 {
+      reader.read_line(&mut reply).unwrap();
+    }
+
+    assert_eq!("OK 1\n5\nOK 1\n5\n", reply);
+  }
+
+  #[test]
+  fn test_long_line_closes_connection() {
+    let dir = TempDir::new("server-long");
+    let path = temp_store(&dir);
+    let server = server(&path, FlushPolicy::Always);
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || server.serve(listener));
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+
+    // one byte over the limit, all of which the server reads before it closes the connection
+    writer.write_all(format!("put key {}", "x".repeat(MAX_LINE_LEN - 7)).as_bytes()).unwrap();
+
+    let mut reply = String::new();
+    reader.read_to_string(&mut reply).unwrap();
+
+    assert_eq!(format!("ERR line longer than {} bytes\n", MAX_LINE_LEN), reply);
+  }
+
+  #[test]
+  fn test_failed_hook_warns() {
+    let dir = TempDir::new("server-warn");
+    let path = temp_store(&dir);
+    let hook = dir.join(".postChange");
+    fs::write(&hook, "#!/usr/bin/env bash\n\nexit 3\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+    let server = server(&path, FlushPolicy::Always);
+
+    let reply = server.handle_line("put key value");
+    assert!(reply.starts_with("WARN the change was saved, but postChange hooks failed: "));
+    assert!(reply.ends_with("OK 1\nok\n"));
+    assert!(KVStore::read_from_file(&path).unwrap().has_key("key"));
+  }
+
+  #[test]
+  fn test_pre_change_hooks_run_unlocked() {
+    let dir = TempDir::new("server-unlocked");
+    let path = temp_store(&dir);
+    let hook = dir.join(".preChange");
+    fs::write(&hook, "#!/usr/bin/env bash\n\necho \"$1 $2 $3\" >> \"$(dirname \"$0\")/runs\"\n[ \"$3\" = slow ] && sleep 1\nexit 0\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+    let server = Arc::new(server(&path, FlushPolicy::Always));
+
+    let slow = server.clone();
+    let writer = thread::spawn(move || slow.handle_line("put key slow"));
+    thread::sleep(Duration::from_millis(300));
+
+    let start = Instant::now();
+    assert_eq!("OK 1\nok\n", server.handle_line("put other fast"));
+    assert!(start.elapsed() < Duration::from_millis(700));
+
+    // changed while the hook of the first put ran, which therefore runs again
+    server.handle_line("put key fast");
+    assert_eq!("OK 1\nok\n", writer.join().unwrap());
+    assert_eq!("OK 1\nslow\n", server.handle_line("get key"));
+
+    let runs = fs::read_to_string(dir.join("runs")).unwrap();
+    assert_eq!(2, runs.lines().filter(|line| *line == "put key slow").count());
+  }
+
+  #[test]
+  fn test_on_miss_hooks_run_unlocked() {
+    let dir = TempDir::new("server-miss");
+    let path = temp_store(&dir);
+    let hook = dir.join(".onMiss");
+    fs::write(&hook, "#!/usr/bin/env bash\n\nsleep 1\necho \"value of $KVS2_KEY\"\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+    let server = Arc::new(server(&path, FlushPolicy::Always));
+
+    let slow = server.clone();
+    let reader = thread::spawn(move || slow.handle_line("get missing"));
+    thread::sleep(Duration::from_millis(300));
+
+    let start = Instant::now();
+    assert_eq!("OK 1\nok\n", server.handle_line("put key value"));
+    assert!(start.elapsed() < Duration::from_millis(700));
+
+    assert_eq!("OK 1\nvalue of missing\n", reader.join().unwrap());
+    assert!(!server.with_store(|kvs| kvs.has_key("missing")));
+  }
+
+  #[test]
+  fn test_gives_up_on_contended_key() {
+    let dir = TempDir::new("server-contended");
+    let path = temp_store(&dir);
+    let hook = dir.join(".preChange");
+    fs::write(&hook, "#!/usr/bin/env bash\n\n[ \"$3\" = slow ] && sleep 0.2\nexit 0\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+    let server = Arc::new(server(&path, FlushPolicy::Never));
+
+    let slow = server.clone();
+    let writer = thread::spawn(move || slow.handle_line("put key slow"));
+
+    // keeps changing the key for longer than the hook of the slow put may run again
+    for i in 0..(MAX_HOOK_RUNS * 20) {
+      server.handle_line(&format!("put key {}", i));
+      thread::sleep(Duration::from_millis(15));
+    }
+
+    assert_eq!("ERR gave up on key as it kept changing while the hooks ran\n", writer.join().unwrap());
+  }
+
+  #[test]
+  fn test_parse_flush_policy() {
+    assert_eq!(FlushPolicy::Always, "always".parse().unwrap());
+    assert_eq!(FlushPolicy::Never, "never".parse().unwrap());
+    assert_eq!(FlushPolicy::Interval(Duration::from_secs(5)), "5".parse().unwrap());
+    assert!("sometimes".parse::<FlushPolicy>().is_err());
+  }
+}
@@ -0,0 +1,168 @@
+use std;
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+
+use ::backend::Backend;
+
+use ::server::Server;
+
+use ::ui::UiError;
+
+type Result<T> = std::result::Result<T, UiError>;
+
+const PROMPT: &str = "kvs2> ";
+
+/// File in the home directory keeping the lines entered in earlier sessions.
+const HISTORY_FILE: &str = ".kvs2_history";
+
+/// What the shell does with a line it has read.
+#[derive(Debug, PartialEq)]
+enum Step {
+  Print(String),
+  /// The change was saved, but a postChange hook failed.
+  Warn(String, String),
+  Fail(String),
+  Skip,
+  Quit,
+}
+
+fn step<B: Backend>(server: &Server<B>, line: &str) -> Step {
+  match line.trim() {
+    "" => Step::Skip,
+    "quit" | "exit" => Step::Quit,
+    line => match server.execute(line) {
+      Ok((result, None)) => Step::Print(result.to_string()),
+      Ok((result, Some(warning))) => Step::Warn(result.to_string(), warning.to_string()),
+      Err(e) => Step::Fail(e.to_string()),
+    },
+  }
+}
+
+/// Whether `line` may be kept in the history file, which is plain text: neither secret values nor
+/// anything typed against an encrypted store are.
+fn keeps_history(line: &str, encrypted: bool) -> bool {
+  !encrypted && !line.split_whitespace().any(|word| word == "--secret")
+}
+
+fn history_path() -> Option<PathBuf> {
+  env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+fn readline_error(e: ReadlineError) -> UiError {
+  match e {
+    ReadlineError::Io(e) => UiError::IoError(e),
+    e => UiError::UnknownError(e.to_string()),
+  }
+}
+
+/// Reads commands from the terminal and runs them against the store of `server`, which stays
+/// loaded and locked until the shell ends with `quit`, `exit` or the end of input.
+///
+/// Besides the store commands, `save` writes the store. When it is written otherwise depends on
+/// the flush policy of `server`; it is always written when the shell ends, also by a signal.
+pub fn run<B: Backend + 'static>(server: Server<B>) -> Result<()> {
+  let server = Arc::new(server);
+  Server::spawn_shutdown_handler(&server);
+  Server::spawn_flusher(&server);
+
+  let mut editor = DefaultEditor::new().map_err(readline_error)?;
+  let history = history_path();
+
+  if let Some(ref history) = history {
+    // there is no history before the first session
+    let _ = editor.load_history(history);
+  }
+
+  let mut error = None;
+
+  loop {
+    let line = match editor.readline(PROMPT) {
+      Ok(line) => line,
+      Err(ReadlineError::Interrupted) => continue,
+      Err(ReadlineError::Eof) => break,
+      Err(e) => {
+        error = Some(readline_error(e));
+        break;
+      },
+    };
+
+    match step(&server, &line) {
+      Step::Print(result) => println!("{}", result),
+      Step::Warn(result, warning) => {
+        println!("{}", result);
+        eprintln!("Warning: {}", warning);
+      },
+      Step::Fail(error) => eprintln!("Error: {}", error),
+      Step::Skip => continue,
+      Step::Quit => break,
+    }
+
+    if keeps_history(&line, server.with_store(|kvs| kvs.backend().is_encrypted())) {
+      let _ = editor.add_history_entry(line.trim());
+    }
+  }
+
+  if let Some(ref history) = history {
+    if let Err(e) = editor.save_history(history) {
+      eprintln!("Error: cannot save the history to {}: {}", history.display(), e);
+    }
+  }
+
+  // the store is written even if the terminal failed, which is reported once it is
+  let flushed = server.flush();
+
+  match (error, flushed) {
+    (Some(e), Err(flush_error)) => {
+      eprintln!("Error: {}", flush_error);
+      Err(e)
+    },
+    (Some(e), Ok(())) => Err(e),
+    (None, flushed) => flushed,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::time::Duration;
+
+  use ::KVStore;
+  use ::hooks::Hooks;
+  use ::server::FlushPolicy;
+  use ::test_util::TempDir;
+  use ::ui::Ui;
+
+  #[test]
+  fn test_step() {
+    let dir = TempDir::new("shell-step");
+
+    let path = dir.join(".kvs.json");
+    ::init(&path).unwrap();
+
+    let ui: Ui = Ui::new("kvs2".to_string(), path.to_str().unwrap().to_string(), false, Hooks::load_from_dir(&dir).unwrap(), Duration::from_secs(1), ::StorageMode::Snapshot);
+    let server = Server::new(ui, FlushPolicy::Never).unwrap();
+
+    assert_eq!(Step::Skip, step(&server, "  "));
+    assert_eq!(Step::Print("ok".to_string()), step(&server, "put key some value"));
+    assert_eq!(Step::Print("some value".to_string()), step(&server, " get key "));
+    assert_eq!(Step::Fail("no value for key missing".to_string()), step(&server, "get missing"));
+    assert!(!KVStore::read_from_file(&path).unwrap().has_key("key"));
+
+    assert_eq!(Step::Print("ok".to_string()), step(&server, "save"));
+    assert!(KVStore::read_from_file(&path).unwrap().has_key("key"));
+    assert_eq!(Step::Quit, step(&server, "exit"));
+  }
+
+  #[test]
+  fn test_keeps_history() {
+    assert!(keeps_history("put key value", false));
+    assert!(!keeps_history("put --secret token abc", false));
+    assert!(!keeps_history("put  --secret", false));
+    assert!(!keeps_history("put key value", true));
+  }
+}
@@ -0,0 +1,44 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{
+  Path,
+  PathBuf,
+};
+use std::process;
+
+/// An empty directory for a test, `kvs2-<name>-<pid>` in the temp dir, removed again when dropped.
+pub struct TempDir {
+  path: PathBuf,
+}
+
+impl TempDir {
+  /// Creates the directory, clearing whatever an earlier run left behind in it.
+  pub fn new(name: &str) -> TempDir {
+    let path = env::temp_dir().join(format!("kvs2-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&path);
+    fs::create_dir_all(&path).unwrap();
+
+    TempDir { path: path }
+  }
+}
+
+impl Deref for TempDir {
+  type Target = Path;
+
+  fn deref(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl AsRef<Path> for TempDir {
+  fn as_ref(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl Drop for TempDir {
+  fn drop(&mut self) {
+    let _ = fs::remove_dir_all(&self.path);
+  }
+}
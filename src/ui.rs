@@ -1,19 +1,51 @@
 use std;
+use std::collections::{
+  BTreeSet,
+  HashMap,
+};
+use std::iter;
+use std::marker::PhantomData;
 use std::path::Path;
+use std::time::Duration;
 use std::fmt::{
+  self,
   Display,
   Formatter,
 };
 
 use ::{
   KVStore,
+  StorageMode,
   ValueType,
 };
 
+use ::oplog;
+
+use ::backend::{
+  Backend,
+  JsonFileBackend,
+};
+
 use ::cmd::Command;
 
-use ::hooks::Hooks;
+use ::hooks::{
+  HookError,
+  HookEvent,
+  Hooks,
+};
+
+use ::listener::{
+  ChangeEvent,
+  ChangeListener,
+};
+
+use ::lock::{
+  LockError,
+  LockMode,
+  StoreLock,
+};
 
+#[derive(Debug)]
 pub enum UiError {
   KvStoreNotExisting(String, String),
   InitWithExistingKvStore(String),
@@ -21,6 +53,14 @@ pub enum UiError {
   AlreadyValuePresent(String),
   KvError(::KVError),
   CmdError(::cmd::Error),
+  LockError(LockError),
+  IoError(std::io::Error),
+  CorruptStore(Vec<String>),
+  HookRejected(String),
+  HookFailed(Vec<String>),
+  HookError(HookError),
+  /// The key kept changing while the hooks for a command on it ran, see `Server`.
+  Contended(String),
   UnknownError(String),
 }
 
@@ -33,6 +73,13 @@ impl Display for UiError {
       UiError::AlreadyValuePresent(ref key) => write!(f, "there is already a value at {}", key),
       UiError::KvError(ref e) => e.fmt(f),
       UiError::CmdError(ref e) => e.fmt(f),
+      UiError::LockError(ref e) => e.fmt(f),
+      UiError::IoError(ref e) => e.fmt(f),
+      UiError::HookRejected(ref stderr) => write!(f, "rejected by the preChange hook: {}", stderr),
+      UiError::HookFailed(ref failures) => write!(f, "the change was saved, but postChange hooks failed:\n{}", failures.join("\n")),
+      UiError::HookError(ref e) => e.fmt(f),
+      UiError::Contended(ref key) => write!(f, "gave up on {} as it kept changing while the hooks ran", key),
+      UiError::CorruptStore(ref problems) => write!(f, "store is corrupt:\n{}", problems.join("\n")),
       UiError::UnknownError(ref msg) => write!(f, "unknown error: {}", msg),
     }
   }
@@ -50,12 +97,38 @@ impl From<::KVError> for UiError {
   }
 }
 
+impl From<LockError> for UiError {
+  fn from(e: LockError) -> Self {
+    UiError::LockError(e)
+  }
+}
+
+impl From<HookError> for UiError {
+  fn from(e: HookError) -> Self {
+    UiError::HookError(e)
+  }
+}
+
+impl From<std::io::Error> for UiError {
+  fn from(e: std::io::Error) -> Self {
+    UiError::IoError(e)
+  }
+}
+
 impl From<String> for UiError {
   fn from(e: String) -> Self {
     UiError::UnknownError(e)
   }
 }
 
+/// What to do about a command once the onMiss hooks were asked about the key it reads.
+pub enum Resolved {
+  /// Run the command, reporting the given result, if any, in place of its own.
+  Run(Command, Option<UiResult>),
+  /// Report the result without running anything.
+  Answered(UiResult),
+}
+
 pub enum UiResult {
   StringValueResult(String),
   StringListResult(Vec<String>),
@@ -87,71 +160,306 @@ impl UiResult {
 
 type Result<T> = std::result::Result<T, UiError>;
 
-#[derive(Debug)]
-pub struct Ui {
+/// Printed in place of the value of a secret key.
+pub const SECRET_MASK: &str = "********";
+
+/// Runs commands against the store at `store_file`, kept in the backend `B`.
+pub struct Ui<B: Backend = JsonFileBackend> {
   program: String,
   store_file: String,
   enumerate_list: bool,
   hooks: Hooks,
+  listeners: Vec<Box<dyn ChangeListener>>,
+  lock_timeout: Duration,
+  storage_mode: StorageMode,
+  backend: PhantomData<fn() -> B>,
+}
+
+impl<B: Backend> fmt::Debug for Ui<B> {
+  fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+    f.debug_struct("Ui")
+      .field("program", &self.program)
+      .field("store_file", &self.store_file)
+      .field("enumerate_list", &self.enumerate_list)
+      .field("hooks", &self.hooks)
+      .field("listeners", &self.listeners.len())
+      .field("lock_timeout", &self.lock_timeout)
+      .field("storage_mode", &self.storage_mode)
+      .finish()
+  }
 }
 
-impl Ui {
-  pub fn new(program: String, store_file: String, enumerate_list: bool, hooks: Hooks) -> Ui {
+impl<B: Backend> Ui<B> {
+  pub fn new(program: String, store_file: String, enumerate_list: bool, hooks: Hooks, lock_timeout: Duration, storage_mode: StorageMode) -> Ui<B> {
     Ui {
       program: program,
       store_file: store_file,
       enumerate_list: enumerate_list,
       hooks: hooks,
+      listeners: Vec::new(),
+      lock_timeout: lock_timeout,
+      storage_mode: storage_mode,
+      backend: PhantomData,
     }
   }
 
-  fn load_or_create_kvstore(&self, store_path: &Path, is_init: bool) -> Result<KVStore> {
-    if !store_path.exists() {
+  pub fn store_path(&self) -> &Path {
+    Path::new(&self.store_file)
+  }
+
+  pub fn lock_timeout(&self) -> Duration {
+    self.lock_timeout
+  }
+
+  pub fn hooks(&self) -> &Hooks {
+    &self.hooks
+  }
+
+  /// Registers `listener` to be told about every change, after the `.postChange` hooks.
+  pub fn add_listener(&mut self, listener: Box<dyn ChangeListener>) {
+    self.listeners.push(listener);
+  }
+
+  /// Loads the existing store, failing if it has not been initialized yet.
+  pub fn open_kvstore(&self) -> Result<KVStore<B>> {
+    self.load_or_create_kvstore(self.store_path(), false)
+  }
+
+  fn load_or_create_kvstore(&self, store_path: &Path, is_init: bool) -> Result<KVStore<B>> {
+    let mut kvs = KVStore::open(store_path);
+
+    if !kvs.exists() {
       if !is_init {
         return Err(UiError::KvStoreNotExisting(store_path.to_str().unwrap_or("<invalid path>").to_string(), self.program.clone()));
       }
 
-      return Ok(::init(store_path)?);
+      // written by `save` once the hooks have accepted the init
+      return Ok(kvs);
     }
 
     if is_init {
       Err(UiError::InitWithExistingKvStore(store_path.to_str().unwrap_or("<invalied path>").to_string()))
     } else {
-      Ok(::KVStore::read_from_file(store_path)?)
+      kvs.load()?;
+      Ok(kvs)
     }
   }
 
+  fn open_unloaded_kvstore(&self, store_path: &Path) -> Result<KVStore<B>> {
+    let kvs = KVStore::open(store_path);
+
+    if !kvs.exists() {
+      return Err(UiError::KvStoreNotExisting(store_path.to_str().unwrap_or("<invalid path>").to_string(), self.program.clone()));
+    }
+
+    Ok(kvs)
+  }
+
+  /// Runs the command in `args` against the store. A failure of the postChange hooks or other
+  /// listeners comes after the change was saved, so it is reported on stderr as a warning and
+  /// the result is returned all the same.
   pub fn run(&self, args: Vec<String>) -> Result<UiResult> {
+    let (result, warning) = self.run_with_warning(args)?;
+
+    if let Some(warning) = warning {
+      eprintln!("Warning: {}", warning);
+    }
+
+    Ok(result)
+  }
+
+  /// Like `run`, but returns the failure of the listeners next to the result.
+  pub fn run_with_warning(&self, args: Vec<String>) -> Result<(UiResult, Option<UiError>)> {
     let store_path = Path::new(&self.store_file);
 
     let command = Command::from_strings(args)?;
 
-    let mut kvs = self.load_or_create_kvstore(store_path, command == Command::Init)?;
+    let lock_mode = if command.may_change() { LockMode::Exclusive } else { LockMode::Shared };
+    let lock = StoreLock::acquire(store_path, lock_mode, self.lock_timeout)?;
+
+    let mut kvs = if command.reads_raw_store() {
+      self.open_unloaded_kvstore(store_path)?
+    } else {
+      self.load_or_create_kvstore(store_path, matches!(command, Command::Init(_)))?
+    };
+
+    let (command, cached) = match self.resolve_get(&kvs, command)? {
+      Resolved::Run(command, cached) => (command, cached),
+      Resolved::Answered(result) => return Ok((result, None)),
+    };
+
+    let mut event = self.change_event(&kvs, &command);
+    self.run_pre_hooks(&command, event.as_ref())?;
 
     let result = self.interpret(&mut kvs, &command)?;
 
-    kvs.write_to_file(store_path)?;
+    if command.is_change() {
+      self.save(&mut kvs, &command)?;
+    }
 
-    self.hooks.run_post_hooks(&result, &command)?;
+    self.finish_change_event(&kvs, &mut event);
 
-    Ok(result)
+    drop(lock);
+
+    let warning = self.notify_listeners(&result, event.as_ref()).err();
+
+    Ok((cached.unwrap_or(result), warning))
   }
 
-  fn interpret(&self, kvs: &mut KVStore, command: &Command) -> Result<UiResult> {
+  /// Asks the onMiss hooks if `command` reads a key missing from `kvs`, see `fetch_missing`.
+  pub fn resolve_get(&self, kvs: &KVStore<B>, command: Command) -> Result<Resolved> {
+    if self.misses(kvs, &command) {
+      self.fetch_missing(command)
+    } else {
+      Ok(Resolved::Run(command, None))
+    }
+  }
+
+  /// Whether `command` reads a key missing from `kvs` that there are onMiss hooks for.
+  pub fn misses(&self, kvs: &KVStore<B>, command: &Command) -> bool {
     match *command {
-      Command::Init => Ok(UiResult::Ok),
+      Command::Get(ref key) | Command::GetRevealed(ref key) | Command::GetCached(ref key) => kvs.get(key).is_none() && self.hooks.has_on_miss(key),
+      _ => false,
+    }
+  }
+
+  /// Runs the onMiss hooks for the key `command` reads, which is not in the store. A `get` is
+  /// answered with the value they provide, a `get --cache` turns into a `put` of it. Other
+  /// commands are returned as they are.
+  pub fn fetch_missing(&self, command: Command) -> Result<Resolved> {
+    let (key, cache) = match command {
+      Command::Get(key) | Command::GetRevealed(key) => (key, false),
+      Command::GetCached(key) => (key, true),
+      command => return Ok(Resolved::Run(command, None)),
+    };
+
+    match self.hooks.run_on_miss_hooks(&key, &self.store_file)? {
+      Some(value) if cache => Ok(Resolved::Run(Command::PutString(key, value.clone()), Some(UiResult::StringValueResult(value)))),
+      Some(value) => Ok(Resolved::Answered(UiResult::StringValueResult(value))),
+      None => Err(UiError::NoValueForKey(key)),
+    }
+  }
+
+  /// Starts the `ChangeEvent` for `command` with the value it is about to change, if there are
+  /// hooks or listeners to tell about it.
+  pub fn change_event(&self, kvs: &KVStore<B>, command: &Command) -> Option<ChangeEvent> {
+    if !command.is_change() || (self.hooks.is_empty() && self.listeners.is_empty()) {
+      return None;
+    }
+
+    let mut event = ChangeEvent::new(command, &self.store_file);
+
+    if let Some(key) = event.key.clone() {
+      event.old_value = kvs.get(&key).cloned();
+      event.secret = kvs.is_secret(&key);
+    }
+
+    Some(event)
+  }
+
+  /// Adds the value after the command to `event`.
+  pub fn finish_change_event(&self, kvs: &KVStore<B>, event: &mut Option<ChangeEvent>) {
+    if let Some(ref mut event) = *event {
+      if let Some(key) = event.key.clone() {
+        event.new_value = kvs.get(&key).cloned();
+        event.secret = event.secret || kvs.is_secret(&key);
+      }
+    }
+  }
+
+  /// Runs the pre change hooks, failing if one of them rejects `command`.
+  pub fn run_pre_hooks(&self, command: &Command, event: Option<&ChangeEvent>) -> Result<()> {
+    match self.hooks.run_pre_hooks(command, event.map(HookEvent::from_change).as_ref())? {
+      Some(stderr) => Err(UiError::HookRejected(stderr)),
+      None => Ok(()),
+    }
+  }
+
+  /// Tells the post change hooks and then the other listeners about `event`, failing with the
+  /// first error once all of them have run.
+  pub fn notify_listeners(&self, result: &UiResult, event: Option<&ChangeEvent>) -> Result<()> {
+    let event = match event {
+      Some(event) => event,
+      None => return Ok(()),
+    };
+
+    let listeners = iter::once(&self.hooks as &dyn ChangeListener).chain(self.listeners.iter().map(|listener| &**listener));
+    let mut first_error = None;
+
+    for listener in listeners {
+      if let Err(e) = listener.on_change(event, result) {
+        first_error = first_error.or(Some(e));
+      }
+    }
+
+    match first_error {
+      Some(e) => Err(e),
+      None => Ok(()),
+    }
+  }
+
+  /// Persists `kvs` after `command` changed it, as configured by the storage mode.
+  pub fn save(&self, kvs: &mut KVStore<B>, command: &Command) -> Result<()> {
+    match (self.storage_mode, kvs.backend().log_path()) {
+      (StorageMode::AppendLog(threshold), Some(ref log_path)) if !command.needs_snapshot() => {
+        let log_size = oplog::append(kvs, log_path, command)?;
+
+        if log_size > threshold {
+          kvs.flush()?;
+        }
+      },
+      _ => kvs.flush()?,
+    }
+
+    Ok(())
+  }
+
+  /// Applies `command` to an already loaded store, without persisting it or running hooks.
+  pub fn interpret(&self, kvs: &mut KVStore<B>, command: &Command) -> Result<UiResult> {
+    match *command {
+      Command::Init(false) => Ok(UiResult::Ok),
+      Command::Init(true) => UiResult::ok(kvs.backend_mut().encrypt()?),
       Command::PutString(ref key, ref value) => self.put_string(key, value, kvs),
-      Command::Get(ref key) => self.get(key, kvs),
+      Command::PutStringWithTtl(ref key, ref value, ttl) => self.put_string_with_ttl(key, value, ttl, kvs),
+      Command::PutSecret(ref key, ref value) => self.put_secret(key, value, kvs),
+      Command::Get(ref key) => self.get(key, false, kvs),
+      Command::GetRevealed(ref key) => self.get(key, true, kvs),
+      Command::GetCached(ref key) => self.get(key, false, kvs),
+      Command::ListHooks(ref command) => self.list_hooks(command),
+      Command::Expire(ref key, ttl) => self.expire(key, ttl, kvs),
+      Command::Ttl(ref key) => self.ttl(key, kvs),
+      Command::Persist(ref key) => self.persist(key, kvs),
+      Command::Purge => Ok(UiResult::StringListResult(kvs.purge_expired())),
       Command::ListKeys => self.list_keys(kvs),
+      Command::Compact => Ok(UiResult::Ok),
+      Command::Convert(format) => UiResult::ok(kvs.backend_mut().set_format(format)?),
+      Command::Verify => self.verify(kvs),
+      Command::Repair(ref target) => self.repair(target, kvs),
       Command::CreateEmptyList(ref key) => self.create_empty_list(key, kvs),
       Command::PushListValue(ref key, ref value) => UiResult::ok(kvs.push_value(key, value)?),
-      Command::PopListValue(ref key) => Ok(UiResult::StringValueResult(kvs.pop_value(key)?)),
+      Command::PushListValues(ref key, ref values) => Ok(UiResult::StringValueResult(kvs.push_values(key, values)?.to_string())),
+      Command::PushFrontListValues(ref key, ref values) => Ok(UiResult::StringValueResult(kvs.push_front_values(key, values)?.to_string())),
+      Command::PopListValue(ref key) => self.pop(key, kvs),
       Command::Drop(ref key) => self.drop(key, kvs),
       Command::ClearList(ref key) => self.clear_list(key, kvs),
+      Command::Increment(ref key, by) => Ok(UiResult::StringValueResult(kvs.increment(key, by)?.to_string())),
+      Command::Decrement(ref key, by) => Ok(UiResult::StringValueResult(kvs.decrement(key, by)?.to_string())),
+      Command::MapSet(ref key, ref field, ref value) => UiResult::ok(kvs.put_map_value(key, field, value).map(|_| ())?),
+      Command::MapGet(ref key, ref field) => self.map_get(key, field, kvs),
+      Command::MapDelete(ref key, ref field) => self.map_delete(key, field, kvs),
+      Command::MapKeys(ref key) => self.map_keys(key, kvs),
+      Command::MapGetAll(ref key) => self.map_get_all(key, kvs),
+      Command::SetAdd(ref key, ref member) => UiResult::ok(kvs.add_set_member(key, member).map(|_| ())?),
+      Command::SetRemove(ref key, ref member) => self.set_remove(key, member, kvs),
+      Command::SetIsMember(ref key, ref member) => self.set_is_member(key, member, kvs),
+      Command::SetCardinality(ref key) => self.set_cardinality(key, kvs),
+      Command::SetUnion(ref keys) => Ok(self.set_result(kvs.set_union(keys)?)),
+      Command::SetIntersection(ref keys) => Ok(self.set_result(kvs.set_intersection(keys)?)),
+      Command::SetDifference(ref keys) => Ok(self.set_result(kvs.set_difference(keys)?)),
     }
   }
 
-  fn put_string(&self, key: &String, value: &String, kvs: &mut KVStore) -> Result<UiResult> {
+  fn put_string(&self, key: &String, value: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
     match kvs.get_value_type(key) {
       Some(ValueType::String) => UiResult::ok(kvs.put(key, value)),
       Some(_) => Err(UiError::AlreadyValuePresent(key.to_string())),
@@ -159,7 +467,58 @@ impl Ui {
     }
   }
 
-  fn create_empty_list(&self, key: &String, kvs: &mut KVStore) -> Result<UiResult> {
+  fn put_string_with_ttl(&self, key: &String, value: &String, ttl: u64, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    self.put_string(key, value, kvs)?;
+    kvs.expire(key, Duration::from_secs(ttl));
+
+    Ok(UiResult::Ok)
+  }
+
+  fn put_secret(&self, key: &String, value: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    self.put_string(key, value, kvs)?;
+    kvs.put_secret(key, value);
+
+    Ok(UiResult::Ok)
+  }
+
+  fn expire(&self, key: &String, ttl: u64, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    if kvs.expire(key, Duration::from_secs(ttl)) {
+      Ok(UiResult::Ok)
+    } else {
+      Err(UiError::NoValueForKey(key.to_string()))
+    }
+  }
+
+  /// Prints the remaining seconds to live of `key`, or -1 if it never expires.
+  fn ttl(&self, key: &String, kvs: &KVStore<B>) -> Result<UiResult> {
+    if !kvs.has_key(key) {
+      return Err(UiError::NoValueForKey(key.to_string()));
+    }
+
+    let ttl = kvs.get_ttl(key).map_or(-1, |d| d.as_secs() as i64);
+
+    Ok(UiResult::StringValueResult(ttl.to_string()))
+  }
+
+  fn persist(&self, key: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    if !kvs.has_key(key) {
+      return Err(UiError::NoValueForKey(key.to_string()));
+    }
+
+    kvs.persist(key);
+
+    Ok(UiResult::Ok)
+  }
+
+  fn pop(&self, key: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    if !kvs.has_key(key) {
+      return Err(UiError::NoValueForKey(key.to_string()));
+    }
+
+    Ok(UiResult::StringValueResult(kvs.pop_value(key)?))
+  }
+
+  fn create_empty_list(&self, key: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
     if kvs.has_key(key) {
       Err(UiError::AlreadyValuePresent(key.to_string()))
     } else {
@@ -168,37 +527,170 @@ impl Ui {
     }
   }
 
-  fn clear_list(&self, key: &String, kvs: &mut KVStore) -> Result<UiResult> {
+  fn clear_list(&self, key: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    let is_secret = kvs.is_secret(key);
+
     match kvs.put_empty_list(key) {
+      Some(_) if is_secret => Ok(UiResult::StringValueResult(SECRET_MASK.to_string())),
       Some(v) => Ok(self.to_result(&v)),
       None => Ok(UiResult::Ok),
     }
   }
 
-  fn drop(&self, key: &String, kvs: &mut KVStore) -> Result<UiResult> {
+  fn drop(&self, key: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    let is_secret = kvs.is_secret(key);
+
     match kvs.drop(key) {
+      Some(_) if is_secret => Ok(UiResult::StringValueResult(SECRET_MASK.to_string())),
       Some(v) => Ok(self.to_result(&v)),
       None => Err(UiError::NoValueForKey(key.to_string())),
     }
   }
 
-  fn list_keys(&self, kvs: &KVStore) -> Result<UiResult> {
+  fn verify(&self, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    let report = kvs.backend_mut().verify()?;
+
+    if report.problems.is_empty() {
+      Ok(UiResult::StringValueResult("ok".to_string()))
+    } else {
+      Err(UiError::CorruptStore(report.problems.iter().map(|p| p.to_string()).collect()))
+    }
+  }
+
+  /// Writes every entry that can still be read into a new store at `target`, by default next to the damaged one.
+  fn repair(&self, target: &Option<String>, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    let target = target.clone().unwrap_or_else(|| format!("{}.repaired", self.store_file));
+    let report = kvs.backend_mut().verify()?;
+
+    let mut repaired: KVStore<B> = KVStore::open(Path::new(&target));
+
+    if repaired.exists() {
+      return Err(UiError::InitWithExistingKvStore(target));
+    }
+
+    if kvs.backend().is_encrypted() {
+      // sealed with the passphrase the store was just opened with, rather than asking for a new one
+      if let Some(passphrase) = kvs.backend().passphrase() {
+        repaired.backend_mut().set_passphrase(passphrase.to_string());
+      }
+      repaired.backend_mut().encrypt()?;
+    }
+
+    let (content, expires, secrets) = report.salvaged.into_entries();
+    let salvaged = content.len();
+
+    for (key, value) in content {
+      repaired.backend_mut().put(key, value);
+    }
+    repaired.backend_mut().expires_mut().extend(expires);
+    repaired.backend_mut().secrets_mut().extend(secrets);
+    repaired.flush()?;
+
+    let mut lines: Vec<String> = report.problems.iter().map(|p| p.to_string()).collect();
+    lines.push(format!("salvaged {} keys into {}", salvaged, target));
+
+    Ok(UiResult::StringListResult(lines))
+  }
+
+  fn list_hooks(&self, command: &Command) -> Result<UiResult> {
+    let hooks = self.hooks.matching(command).into_iter()
+      .map(|(stage, hook)| format!("{} {}", stage, hook.run.display()))
+      .collect();
+
+    Ok(UiResult::StringListResult(hooks))
+  }
+
+  fn list_keys(&self, kvs: &KVStore<B>) -> Result<UiResult> {
     Ok(UiResult::StringListResult(kvs.get_keys().iter().map(|x| x.to_string()).collect()))
   }
 
-  fn get(&self, key: &String, kvs: &KVStore) -> Result<UiResult> {
+  fn get(&self, key: &String, reveal: bool, kvs: &KVStore<B>) -> Result<UiResult> {
+    // the onMiss hooks have been asked before, see `resolve_get`
     let value = kvs.get(key).ok_or(UiError::NoValueForKey(key.clone()))?;
 
+    if !reveal && kvs.is_secret(key) {
+      return Ok(UiResult::StringValueResult(SECRET_MASK.to_string()));
+    }
+
     Ok(self.to_result(value))
   }
 
+  fn get_map<'a>(&self, key: &String, kvs: &'a KVStore<B>) -> Result<&'a HashMap<String, String>> {
+    kvs.get_map(key)?.ok_or(UiError::NoValueForKey(key.clone()))
+  }
+
+  fn map_get(&self, key: &String, field: &String, kvs: &KVStore<B>) -> Result<UiResult> {
+    let value = self.get_map(key, kvs)?.get(field).ok_or(UiError::NoValueForKey(format!("{} {}", key, field)))?;
+
+    Ok(UiResult::StringValueResult(value.clone()))
+  }
+
+  fn map_delete(&self, key: &String, field: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    if !kvs.has_key(key) {
+      return Err(UiError::NoValueForKey(key.clone()));
+    }
+
+    match kvs.remove_map_value(key, field)? {
+      Some(v) => Ok(UiResult::StringValueResult(v)),
+      None => Err(UiError::NoValueForKey(format!("{} {}", key, field))),
+    }
+  }
+
+  fn map_keys(&self, key: &String, kvs: &KVStore<B>) -> Result<UiResult> {
+    let mut fields: Vec<String> = self.get_map(key, kvs)?.keys().cloned().collect();
+    fields.sort();
+
+    Ok(UiResult::StringListResult(fields))
+  }
+
+  fn map_get_all(&self, key: &String, kvs: &KVStore<B>) -> Result<UiResult> {
+    Ok(UiResult::StringListResult(self.prepare_map_result(self.get_map(key, kvs)?)))
+  }
+
+  fn set_remove(&self, key: &String, member: &String, kvs: &mut KVStore<B>) -> Result<UiResult> {
+    if !kvs.has_key(key) {
+      return Err(UiError::NoValueForKey(key.clone()));
+    }
+
+    if kvs.remove_set_member(key, member)? {
+      Ok(UiResult::Ok)
+    } else {
+      Err(UiError::NoValueForKey(format!("{} {}", key, member)))
+    }
+  }
+
+  fn set_is_member(&self, key: &String, member: &String, kvs: &KVStore<B>) -> Result<UiResult> {
+    let is_member = kvs.get_set(key)?.is_some_and(|set| set.contains(member));
+
+    Ok(UiResult::StringValueResult(is_member.to_string()))
+  }
+
+  fn set_cardinality(&self, key: &String, kvs: &KVStore<B>) -> Result<UiResult> {
+    let cardinality = kvs.get_set(key)?.map_or(0, |set| set.len());
+
+    Ok(UiResult::StringValueResult(cardinality.to_string()))
+  }
+
+  fn set_result(&self, set: BTreeSet<String>) -> UiResult {
+    UiResult::StringListResult(set.into_iter().collect())
+  }
+
   fn to_result(&self, value: &::Value) -> UiResult {
     match *value {
       ::Value::StringValue(ref val) => UiResult::StringValueResult(val.clone()),
       ::Value::ListValue(ref list) => UiResult::StringListResult(self.prepare_list_result(list)),
+      ::Value::IntValue(i) => UiResult::StringValueResult(i.to_string()),
+      ::Value::MapValue(ref map) => UiResult::StringListResult(self.prepare_map_result(map)),
+      ::Value::SetValue(ref set) => self.set_result(set.clone()),
     }
   }
 
+  fn prepare_map_result(&self, map: &HashMap<String, String>) -> Vec<String> {
+    let mut entries: Vec<String> = map.iter().map(|(field, value)| format!("{}: {}", field, value)).collect();
+    entries.sort();
+    entries
+  }
+
   fn prepare_list_result(&self, list: &Vec<String>) -> Vec<String> {
     if self.enumerate_list {
       let mut i = 0;
@@ -216,8 +708,177 @@ impl Ui {
 mod tests {
   use super::*;
 
+  use std::fs;
+  use std::os::unix::fs::PermissionsExt;
+  use std::sync::{
+    Arc,
+    Mutex,
+  };
+
+  use serde_json;
+  use serde_json::Value as JsonValue;
+
+  use ::test_util::TempDir;
+
   #[test]
   fn test_construct() {
-    Ui::new("program".to_string(), "test".to_string(), false);
+    Ui::<JsonFileBackend>::new("program".to_string(), "test".to_string(), false, Hooks::load_from_dir("test/hooks").unwrap(), Duration::from_secs(1), StorageMode::Snapshot);
+  }
+
+  #[test]
+  fn test_failed_post_change_hook_keeps_result() {
+    let dir = TempDir::new("ui-failed-hook");
+
+    let hook = dir.join(".postChange");
+    fs::write(&hook, "#!/usr/bin/env bash\n\nexit 1\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let store = dir.join(".kvs.json").to_str().unwrap().to_string();
+    let ui = Ui::<JsonFileBackend>::new("program".to_string(), store, false, Hooks::load_from_dir(&dir).unwrap(), Duration::from_secs(1), StorageMode::Snapshot);
+    let run = |line: &str| ui.run_with_warning(line.split(' ').map(str::to_string).collect()).unwrap();
+
+    run("init");
+    run("emptyList list");
+    run("push list a");
+
+    let (result, warning) = run("pop list");
+    assert_eq!("a", result.to_string());
+    match warning {
+      Some(UiError::HookFailed(ref failures)) => assert_eq!(1, failures.len()),
+      other => panic!("unexpected warning {:?}", other),
+    }
+
+    run("push list b");
+    assert_eq!("b", ui.run(vec!["pop".to_string(), "list".to_string()]).unwrap().to_string());
+  }
+
+  #[test]
+  fn test_json_hook_event() {
+    let dir = TempDir::new("ui-event");
+
+    let hook = dir.join(".postChange");
+    fs::write(&hook, "#!/usr/bin/env bash\n\ncat - > \"$(dirname \"$0\")/event.json\"\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let store = dir.join(".kvs.json").to_str().unwrap().to_string();
+    let mut hooks = Hooks::load_from_dir(&dir).unwrap();
+    hooks.set_json_events(true);
+    let ui = Ui::<JsonFileBackend>::new("program".to_string(), store.clone(), false, hooks, Duration::from_secs(1), StorageMode::Snapshot);
+    let run = |line: &str| ui.run(line.split(' ').map(str::to_string).collect()).unwrap();
+
+    run("init");
+    run("emptyList list");
+    run("push list a b");
+
+    let event: JsonValue = serde_json::from_slice(&fs::read(dir.join("event.json")).unwrap()).unwrap();
+    assert_eq!(Some("push"), event.get("command").and_then(JsonValue::as_str));
+    assert_eq!(Some("list"), event.get("key").and_then(JsonValue::as_str));
+    assert_eq!(Some(&serde_json::to_value(vec!["a b"]).unwrap()), event.get("value"));
+    assert_eq!(Some(&serde_json::to_value(Vec::<String>::new()).unwrap()), event.get("previous_value"));
+    assert_eq!(Some(&serde_json::to_value(vec!["a b"]).unwrap()), event.get("new_value"));
+    assert_eq!(Some("list"), event.get("value_type").and_then(JsonValue::as_str));
+    assert_eq!(Some(store.as_str()), event.get("store").and_then(JsonValue::as_str));
+
+    run("put --secret token abc");
+    let event: JsonValue = serde_json::from_slice(&fs::read(dir.join("event.json")).unwrap()).unwrap();
+    assert_eq!(Some(SECRET_MASK), event.get("new_value").and_then(JsonValue::as_str));
+  }
+
+  struct Recorder(Arc<Mutex<Vec<ChangeEvent>>>);
+
+  impl ChangeListener for Recorder {
+    fn on_change(&self, event: &ChangeEvent, _: &UiResult) -> Result<()> {
+      self.0.lock().unwrap().push(event.clone());
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test_change_listener() {
+    let dir = TempDir::new("ui-listener");
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let store = dir.join(".kvs.json").to_str().unwrap().to_string();
+    let mut ui = Ui::<JsonFileBackend>::new("program".to_string(), store, false, Hooks::load_from_dir(&dir).unwrap(), Duration::from_secs(1), StorageMode::Snapshot);
+    ui.add_listener(Box::new(Recorder(events.clone())));
+    let run = |line: &str| ui.run(line.split(' ').map(str::to_string).collect()).unwrap();
+
+    run("init");
+    run("incr counter");
+    run("incr counter 2");
+    run("get counter");
+
+    let events = events.lock().unwrap();
+    assert_eq!(3, events.len());
+    assert_eq!(Command::Increment("counter".to_string(), 2), events[2].command);
+    assert_eq!(Some("counter".to_string()), events[2].key);
+    assert_eq!(Some(::Value::IntValue(1)), events[2].old_value);
+    assert_eq!(Some(::Value::IntValue(3)), events[2].new_value);
+  }
+
+  #[test]
+  fn test_on_miss_hook() {
+    let dir = TempDir::new("ui-miss");
+
+    let hook = dir.join(".onMiss");
+    fs::write(&hook, "#!/usr/bin/env bash\n\necho \"fetched $2\"\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let store = dir.join(".kvs.json").to_str().unwrap().to_string();
+    let ui = Ui::<JsonFileBackend>::new("program".to_string(), store, false, Hooks::load_from_dir(&dir).unwrap(), Duration::from_secs(1), StorageMode::Snapshot);
+    let run = |line: &str| ui.run(line.split(' ').map(str::to_string).collect()).unwrap().to_string();
+
+    run("init");
+    run("put stored value");
+    assert_eq!("value", run("get --cache stored"));
+
+    assert_eq!("fetched host", run("get host"));
+    assert!(!ui.open_kvstore().unwrap().has_key("host"));
+
+    assert_eq!("fetched host", run("get --cache host"));
+    assert_eq!(Some(&::Value::StringValue("fetched host".to_string())), ui.open_kvstore().unwrap().get(&"host"));
+  }
+
+  #[test]
+  fn test_secret_is_masked() {
+    let ui = Ui::<::backend::MemoryBackend>::new("program".to_string(), "test".to_string(), false, Hooks::load_from_dir("test/hooks").unwrap(), Duration::from_secs(1), StorageMode::Snapshot);
+    let mut kvs = KVStore::new();
+    let get = |ui: &Ui<_>, kvs: &mut KVStore<_>, line: &str| ui.interpret(kvs, &line.parse().unwrap()).unwrap().to_string();
+
+    get(&ui, &mut kvs, "put --secret token abc");
+    assert_eq!(SECRET_MASK, get(&ui, &mut kvs, "get token"));
+    assert_eq!("abc", get(&ui, &mut kvs, "get --reveal token"));
+
+    get(&ui, &mut kvs, "put token public");
+    assert_eq!("public", get(&ui, &mut kvs, "get token"));
+
+    get(&ui, &mut kvs, "put --secret cleared abc");
+    assert_eq!(SECRET_MASK, get(&ui, &mut kvs, "clear cleared"));
+    get(&ui, &mut kvs, "put --secret dropped abc");
+    assert_eq!(SECRET_MASK, get(&ui, &mut kvs, "drop dropped"));
+  }
+
+  #[test]
+  fn test_repair_keeps_passphrase() {
+    let dir = TempDir::new("ui-repair");
+    let path = dir.join(".kvs.json");
+    let target = dir.join("repaired.json");
+
+    let mut kvs: KVStore = KVStore::open(&path);
+    kvs.backend_mut().set_passphrase("secret".to_string());
+    kvs.backend_mut().encrypt().unwrap();
+    kvs.put("key", "value");
+    kvs.flush().unwrap();
+
+    let ui = Ui::<JsonFileBackend>::new("program".to_string(), path.to_str().unwrap().to_string(), false, Hooks::load_from_dir(&dir).unwrap(), Duration::from_secs(1), StorageMode::Snapshot);
+    let mut kvs: KVStore = KVStore::open(&path);
+    kvs.backend_mut().set_passphrase("secret".to_string());
+    ui.interpret(&mut kvs, &Command::Repair(Some(target.to_str().unwrap().to_string()))).unwrap();
+
+    let mut repaired: KVStore = KVStore::open(&target);
+    repaired.backend_mut().set_passphrase("secret".to_string());
+    repaired.load().unwrap();
+    assert!(repaired.backend().is_encrypted());
+    assert!(repaired.has_key("key"));
   }
 }
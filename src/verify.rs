@@ -0,0 +1,356 @@
+use std;
+use std::collections::HashMap;
+use std::fmt::{
+  Display,
+  Formatter,
+};
+
+use serde;
+use serde_json;
+
+use ::Value;
+
+use ::backend::{
+  Backend,
+  MemoryBackend,
+};
+
+use ::format::{
+  self,
+  Format,
+  FORMAT_VERSION,
+};
+
+/// Damage found in a store, located by byte offset and, where known, by key.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Problem {
+  pub offset: usize,
+  pub key: Option<String>,
+  pub message: String,
+}
+
+impl Display for Problem {
+  fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+    match self.key {
+      Some(ref key) => write!(f, "byte {} (key {}): {}", self.offset, key, self.message),
+      None => write!(f, "byte {}: {}", self.offset, self.message),
+    }
+  }
+}
+
+/// What `verify` found in a store: its problems and every entry that could still be read.
+#[derive(Debug)]
+pub struct Report {
+  pub problems: Vec<Problem>,
+  pub salvaged: MemoryBackend,
+}
+
+/// Checks a store file in any `Format`, salvaging every entry that can be parsed.
+pub fn verify(bytes: &[u8]) -> Report {
+  match Format::detect(bytes) {
+    Format::Json => verify_json(bytes),
+    Format::Binary => format::salvage_binary(bytes),
+  }
+}
+
+type ScanResult<T> = std::result::Result<T, Problem>;
+
+/// Walks the structure of a JSON store without parsing it as a whole, so that a damaged entry
+/// does not hide the others.
+struct Scanner<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+}
+
+/// Splits the position off a serde_json error message and turns it into an offset into `slice`.
+fn locate_error(slice: &[u8], e: &serde_json::Error) -> (usize, String) {
+  let message = e.to_string();
+
+  let position = message.rfind(" at line ").and_then(|i| {
+    let mut numbers = message[i + 9..].split(" column ").map(|n| n.parse::<usize>().ok());
+
+    match (numbers.next(), numbers.next()) {
+      (Some(Some(line)), Some(Some(column))) => Some((i, line, column)),
+      _ => None,
+    }
+  });
+
+  match position {
+    Some((i, line, column)) => {
+      let line_start = slice.split(|&b| b == b'\n').take(line.saturating_sub(1)).map(|l| l.len() + 1).sum::<usize>();
+      ((line_start + column.saturating_sub(1)).min(slice.len()), message[..i].to_string())
+    },
+    None => (0, message),
+  }
+}
+
+impl<'a> Scanner<'a> {
+  fn problem<S: ToString>(&self, key: Option<&str>, message: S) -> Problem {
+    Problem {
+      offset: self.pos,
+      key: key.map(str::to_string),
+      message: message.to_string(),
+    }
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.pos < self.bytes.len() && (self.bytes[self.pos] as char).is_whitespace() {
+      self.pos += 1;
+    }
+  }
+
+  fn peek(&self, key: Option<&str>) -> ScanResult<u8> {
+    self.bytes.get(self.pos).cloned().ok_or_else(|| self.problem(key, "unexpected end of file"))
+  }
+
+  fn expect(&mut self, c: u8, key: Option<&str>) -> ScanResult<()> {
+    self.skip_whitespace();
+
+    if self.peek(key)? != c {
+      return Err(self.problem(key, format!("expected `{}`", c as char)));
+    }
+
+    self.pos += 1;
+    Ok(())
+  }
+
+  fn skip_string(&mut self, key: Option<&str>) -> ScanResult<()> {
+    self.expect(b'"', key)?;
+
+    loop {
+      match self.peek(key)? {
+        b'"' => {
+          self.pos += 1;
+          return Ok(());
+        },
+        b'\\' => self.pos += 2,
+        _ => self.pos += 1,
+      }
+    }
+  }
+
+  /// Skips a complete JSON value and returns its bytes.
+  fn skip_value(&mut self, key: Option<&str>) -> ScanResult<&'a [u8]> {
+    self.skip_whitespace();
+    let start = self.pos;
+    let mut depth = 0;
+
+    loop {
+      let c = self.peek(key)?;
+
+      match c {
+        b'"' => self.skip_string(key)?,
+        b'{' | b'[' => {
+          depth += 1;
+          self.pos += 1;
+        },
+        b'}' | b']' if depth > 0 => {
+          depth -= 1;
+          self.pos += 1;
+        },
+        b',' | b'}' | b']' => break,
+        c if depth == 0 && (c as char).is_whitespace() => break,
+        _ => self.pos += 1,
+      }
+
+      // strings, objects and arrays end with their closing character, other values at a delimiter
+      if depth == 0 && matches!(c, b'"' | b'}' | b']') {
+        break;
+      }
+    }
+
+    Ok(&self.bytes[start..self.pos])
+  }
+
+  fn parse<T: serde::Deserialize>(&self, slice: &[u8], key: Option<&str>) -> ScanResult<T> {
+    serde_json::from_slice(slice).map_err(|e| {
+      let (offset, message) = locate_error(slice, &e);
+
+      Problem {
+        offset: self.pos - slice.len() + offset,
+        key: key.map(str::to_string),
+        message: message,
+      }
+    })
+  }
+
+  /// Calls `member` with the key and offset of each member of the object at the current position.
+  /// `member` has to consume the value.
+  fn members<F>(&mut self, key: Option<&str>, mut member: F) -> ScanResult<()>
+    where F: FnMut(&mut Scanner<'a>, String, usize) -> ScanResult<()> {
+    self.expect(b'{', key)?;
+    self.skip_whitespace();
+
+    if self.peek(key)? == b'}' {
+      self.pos += 1;
+      return Ok(());
+    }
+
+    loop {
+      self.skip_whitespace();
+      let offset = self.pos;
+      let name_bytes = {
+        self.skip_string(key)?;
+        &self.bytes[offset..self.pos]
+      };
+      let name: String = self.parse(name_bytes, key)?;
+
+      self.expect(b':', Some(&name))?;
+      member(self, name, offset)?;
+
+      self.skip_whitespace();
+      match self.peek(key)? {
+        b',' => self.pos += 1,
+        b'}' => {
+          self.pos += 1;
+          return Ok(());
+        },
+        _ => return Err(self.problem(key, "expected `,` or `}`")),
+      }
+    }
+  }
+}
+
+fn verify_json(bytes: &[u8]) -> Report {
+  let mut scanner = Scanner { bytes: bytes, pos: 0 };
+  let mut problems = Vec::new();
+  let mut salvaged = MemoryBackend::new();
+  let mut checksum_offset = 0;
+
+  let result = scanner.members(None, |scanner, name, offset| {
+    match name.as_str() {
+      "content" => scanner.members(None, |scanner, key, _| {
+        let slice = scanner.skip_value(Some(&key))?;
+
+        match scanner.parse::<Value>(slice, Some(&key)) {
+          Ok(value) => { salvaged.put(key, value); },
+          Err(problem) => problems.push(problem),
+        }
+
+        Ok(())
+      }),
+      "expires" => {
+        let slice = scanner.skip_value(None)?;
+
+        match scanner.parse::<HashMap<String, u64>>(slice, None) {
+          Ok(expires) => salvaged.expires_mut().extend(expires),
+          Err(problem) => problems.push(problem),
+        }
+
+        Ok(())
+      },
+      "secrets" => {
+        let slice = scanner.skip_value(None)?;
+
+        match scanner.parse::<Vec<String>>(slice, None) {
+          Ok(secrets) => salvaged.secrets_mut().extend(secrets),
+          Err(problem) => problems.push(problem),
+        }
+
+        Ok(())
+      },
+      "version" => {
+        let slice = scanner.skip_value(None)?;
+
+        match scanner.parse::<u64>(slice, None) {
+          Ok(version) if version > FORMAT_VERSION => problems.push(Problem {
+            offset: offset,
+            key: None,
+            message: format!("format version {} is newer than the supported version {}", version, FORMAT_VERSION),
+          }),
+          Ok(_) => (),
+          Err(problem) => problems.push(problem),
+        }
+
+        Ok(())
+      },
+      "checksum" => {
+        checksum_offset = offset;
+        scanner.skip_value(None).map(|_| ())
+      },
+      _ => scanner.skip_value(None).map(|_| ()),
+    }
+  });
+
+  if let Err(problem) = result {
+    problems.push(problem);
+  }
+
+  if problems.is_empty() {
+    if let Err(e) = format::read_json(bytes) {
+      problems.push(Problem { offset: checksum_offset, key: None, message: e.to_string() });
+    }
+  }
+
+  Report {
+    problems: problems,
+    salvaged: salvaged,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use ::KVStore;
+
+  fn store_bytes() -> Vec<u8> {
+    let mut kvs = KVStore::new();
+    kvs.put("first", "one");
+    kvs.put("second", "two");
+    kvs.put_empty_list("third");
+
+    let mut bytes = Vec::new();
+    kvs.write(&mut bytes).unwrap();
+    bytes
+  }
+
+  fn find(bytes: &[u8], needle: &str) -> usize {
+    String::from_utf8_lossy(bytes).find(needle).unwrap()
+  }
+
+  #[test]
+  fn test_intact_store() {
+    let report = verify(&store_bytes());
+
+    assert!(report.problems.is_empty());
+    assert_eq!(3, report.salvaged.keys().len());
+  }
+
+  #[test]
+  fn test_damaged_entry() {
+    let bytes = String::from_utf8(store_bytes()).unwrap().replace("\"StringValue\": \"two\"", "\"StringValue\": 2").into_bytes();
+    let report = verify(&bytes);
+
+    assert_eq!(1, report.problems.len());
+    assert_eq!(Some("second".to_string()), report.problems[0].key);
+    assert_eq!(find(&bytes, ": 2") + 2, report.problems[0].offset);
+    assert_eq!(2, report.salvaged.keys().len());
+  }
+
+  #[test]
+  fn test_truncated_store() {
+    let bytes = store_bytes();
+    let cut = find(&bytes, "\"third\"") + 12;
+    let report = verify(&bytes[..cut]);
+
+    assert_eq!(1, report.problems.len());
+    assert_eq!(Some("third".to_string()), report.problems[0].key);
+    assert_eq!(cut, report.problems[0].offset);
+  }
+
+  #[test]
+  fn test_edited_store_fails_checksum() {
+    let bytes = String::from_utf8(store_bytes()).unwrap().replace("\"one\"", "\"uno\"").into_bytes();
+
+    match KVStore::read(&bytes[..]) {
+      Err(::KVError::ChecksumMismatch(_)) => (),
+      other => panic!("unexpected result {:?}", other),
+    }
+
+    let report = verify(&bytes);
+    assert_eq!(1, report.problems.len());
+    assert_eq!(find(&bytes, "\"checksum\""), report.problems[0].offset);
+    assert_eq!(3, report.salvaged.keys().len());
+  }
+}